@@ -0,0 +1,458 @@
+use crate::git::{self, Origin};
+use log::error;
+use min_auth_common::{
+    config::admin::FsConfig,
+    data::{
+        requests::RequestContent, tokens::ApiToken, users::User, DataSaver, LoadReport, TreeLock,
+    },
+    error::{Error, ErrorKind},
+    utils::genid::genid,
+};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+// Secrets generated while applying a request. They are shown once and never stored.
+#[derive(Serialize, Default, PartialEq, Debug)]
+pub struct Applied {
+    pub user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+// Apply the content of a request to the user JSON files.
+// Changes are serialized, so none is lost or checked against a stale directory.
+pub fn apply<P>(content: &RequestContent, users_dir: P, secret: &str) -> Result<Applied, Error>
+where
+    P: AsRef<Path>,
+{
+    let users_dir = users_dir.as_ref();
+    let _lock = TreeLock::exclusive(users_dir)?;
+    change(content, users_dir, secret)
+}
+
+// Apply a change and commit it if the user directory is a git repository.
+// The commit is made under the same lock, so that it holds this change only.
+// If it fails, the change is rolled back and the error returned.
+pub fn apply_and_commit(
+    content: &RequestContent,
+    file_system: &FsConfig,
+    secret: &str,
+    origin: &Origin,
+) -> Result<Applied, Error> {
+    let users_dir = Path::new(&file_system.users);
+    let _lock = TreeLock::exclusive(users_dir)?;
+    let config = match &file_system.git {
+        Some(config) => config,
+        None => return change(content, users_dir, secret),
+    };
+    let previous = match content.user_id() {
+        Some(user_id) => Some(User::find(users_dir, user_id)?),
+        None => None,
+    };
+    let applied = change(content, users_dir, secret)?;
+    let summary = format!("{} {}", content.kind(), applied.user_id);
+    if let Err(e) = git::commit(users_dir, config, &applied.user_id, &summary, origin) {
+        if let Err(e) = roll_back(users_dir, &applied.user_id, previous) {
+            error!("{} could not be rolled back: {} ({})", summary, e, e.code());
+        }
+        return Err(e);
+    }
+    Ok(applied)
+}
+
+// Restore a user as it was before a change, or remove a created one.
+fn roll_back(
+    users_dir: &Path,
+    user_id: &str,
+    previous: Option<(PathBuf, User)>,
+) -> Result<(), Error> {
+    match previous {
+        Some((path, user)) => user.save(path)?,
+        None => User::remove(User::find(users_dir, user_id)?.0)?,
+    }
+    Ok(())
+}
+
+fn change(content: &RequestContent, users_dir: &Path, secret: &str) -> Result<Applied, Error> {
+    if let RequestContent::CreateUser(_) | RequestContent::UpdateUser(_) = content {
+        content.validate(&directory(users_dir)?)?;
+    }
+    match content {
+        RequestContent::CreateUser(content) => {
+            let mut user = User {
+                id: genid(),
+                username: content.username.clone(),
+                email: content.email.clone(),
+                salt: String::new(),
+                password_hash: String::new(),
+                pubkey_fpr: String::new(),
+                superuser: content.superuser,
+                acl: content.acl.clone(),
+                tokens: vec![],
+                totp_secret: None,
+                webauthn: vec![],
+            };
+            let password = user.renew_password(secret);
+            let path = users_dir.join(format!("{}.json", user.id));
+            user.save_new(path)?;
+            Ok(Applied {
+                user_id: user.id,
+                password: Some(password),
+                ..Default::default()
+            })
+        }
+        RequestContent::UpdateUser(content) => {
+            if content.renew_pubkey {
+                return Err(Error::new(
+                    ErrorKind::BadRequest,
+                    "A public key cannot be renewed without the key itself.",
+                ));
+            }
+            let (path, mut user) = User::find(users_dir, &content.user_id)?;
+            let mut applied = Applied {
+                user_id: user.id.clone(),
+                ..Default::default()
+            };
+            if let Some(username) = &content.username {
+                user.username = username.clone();
+            }
+            if let Some(email) = &content.email {
+                user.email = email.clone();
+            }
+            if let Some(superuser) = content.superuser {
+                user.superuser = superuser;
+            }
+            if let Some(acl) = &content.acl {
+                user.acl = acl.clone();
+            }
+            if content.renew_password {
+                applied.password = Some(user.renew_password(secret));
+            }
+            if content.renew_totp {
+                applied.totp_secret = Some(user.renew_totp());
+            }
+            user.save(&path)?;
+            Ok(applied)
+        }
+        RequestContent::DeleteUser(content) => {
+            let (path, user) = User::find(users_dir, &content.user_id)?;
+            User::remove(path)?;
+            Ok(Applied {
+                user_id: user.id,
+                ..Default::default()
+            })
+        }
+        RequestContent::IssueToken(content) => {
+            let (path, mut user) = User::find(users_dir, &content.user_id)?;
+            let (token, plain) = ApiToken::issue(
+                secret,
+                &content.label,
+                content.acl.clone(),
+                content.expires_at,
+            );
+            user.tokens.push(token);
+            user.save(&path)?;
+            Ok(Applied {
+                user_id: user.id,
+                token: Some(plain),
+                ..Default::default()
+            })
+        }
+        RequestContent::RevokeToken(content) => {
+            let (path, mut user) = User::find(users_dir, &content.user_id)?;
+            let len = user.tokens.len();
+            user.tokens.retain(|token| token.id != content.token_id);
+            if user.tokens.len() == len {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Token {} of {} was not found.", content.token_id, user.id),
+                ));
+            }
+            user.save(&path)?;
+            Ok(Applied {
+                user_id: user.id,
+                ..Default::default()
+            })
+        }
+        RequestContent::RegisterWebAuthn(content) => {
+            let (path, mut user) = User::find(users_dir, &content.user_id)?;
+            if user.webauthn.iter().any(|c| c.id == content.credential.id) {
+                return Err(Error::new(
+                    ErrorKind::BadRequest,
+                    format!(
+                        "Credential {} of {} is already registered.",
+                        content.credential.id, user.id
+                    ),
+                ));
+            }
+            user.webauthn.push(content.credential.clone());
+            user.save(&path)?;
+            Ok(Applied {
+                user_id: user.id,
+                ..Default::default()
+            })
+        }
+        RequestContent::RemoveWebAuthn(content) => {
+            let (path, mut user) = User::find(users_dir, &content.user_id)?;
+            let len = user.webauthn.len();
+            user.webauthn.retain(|c| c.id != content.credential_id);
+            if user.webauthn.len() == len {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Credential {} of {} was not found.",
+                        content.credential_id, user.id
+                    ),
+                ));
+            }
+            user.save(&path)?;
+            Ok(Applied {
+                user_id: user.id,
+                ..Default::default()
+            })
+        }
+    }
+}
+
+// Strict, as a user left out could not be checked for conflicts.
+pub(crate) fn directory(users_dir: &Path) -> Result<Vec<User>, Error> {
+    LoadReport::<User>::load(users_dir)?.into_items(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use min_auth_common::{
+        config::admin::GitConfig,
+        data::{
+            requests::{
+                CreateUserRequest, DeleteUserRequest, IssueTokenRequest, RevokeTokenRequest,
+                UpdateUserRequest,
+            },
+            tokens::hash_token,
+            users::{AccessControl, AccessControlKind},
+            DataLoader,
+        },
+    };
+    use std::{sync::Barrier, thread};
+    use tempfile::tempdir;
+
+    fn create(dir: &Path) -> Applied {
+        let content = RequestContent::CreateUser(CreateUserRequest {
+            username: "new-user".to_string(),
+            email: "new-user@example.com".to_string(),
+            superuser: false,
+            acl: vec![AccessControl {
+                control: AccessControlKind::Allow,
+                service: "service 1".to_string(),
+            }],
+        });
+        apply(&content, dir, "secret").unwrap()
+    }
+
+    #[test]
+    fn test_create_and_update() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        let created = create(dir);
+        let path = dir.join(format!("{}.json", created.user_id));
+        let user = User::load(&path).unwrap();
+        assert_eq!(user.username, "new-user".to_string());
+        assert!(user.verify("secret", created.password.unwrap()));
+
+        let content = RequestContent::UpdateUser(UpdateUserRequest {
+            user_id: created.user_id.clone(),
+            username: None,
+            email: Some("renamed@example.com".to_string()),
+            superuser: Some(true),
+            acl: None,
+            renew_password: true,
+            renew_pubkey: false,
+            renew_totp: true,
+        });
+        let updated = apply(&content, dir, "secret").unwrap();
+        let user = User::load(&path).unwrap();
+        assert_eq!(user.username, "new-user".to_string());
+        assert_eq!(user.email, "renamed@example.com".to_string());
+        assert!(user.superuser);
+        assert_eq!(user.acl.len(), 1);
+        assert!(user.verify("secret", updated.password.unwrap()));
+        assert_eq!(user.totp_secret, updated.totp_secret);
+
+        let content = RequestContent::DeleteUser(DeleteUserRequest {
+            user_id: created.user_id.clone(),
+        });
+        apply(&content, dir, "secret").unwrap();
+        assert!(!path.exists());
+        let e = apply(&content, dir, "secret").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnknownUser);
+    }
+
+    #[test]
+    fn test_validation() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        let created = create(dir);
+
+        // Taken regardless of case
+        let content = RequestContent::CreateUser(CreateUserRequest {
+            username: "NEW-USER".to_string(),
+            email: "invalid".to_string(),
+            superuser: false,
+            acl: vec![],
+        });
+        let e = apply(&content, dir, "secret").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadRequest);
+        assert!(e.to_string().contains("taken"));
+        assert!(e.to_string().contains("has no @"));
+        assert_eq!(User::load_all(dir, true).unwrap().len(), 1);
+
+        // The user's own email is not a conflict.
+        let content = RequestContent::UpdateUser(UpdateUserRequest {
+            user_id: created.user_id.clone(),
+            username: Some("renamed".to_string()),
+            email: Some("NEW-USER@example.com".to_string()),
+            superuser: None,
+            acl: None,
+            renew_password: false,
+            renew_pubkey: false,
+            renew_totp: false,
+        });
+        apply(&content, dir, "secret").unwrap();
+    }
+
+    #[test]
+    fn test_tokens() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        let created = create(dir);
+        let path = dir.join(format!("{}.json", created.user_id));
+
+        let content = RequestContent::IssueToken(IssueTokenRequest {
+            user_id: created.user_id.clone(),
+            label: "ci".to_string(),
+            acl: vec![],
+            expires_at: None,
+        });
+        let issued = apply(&content, dir, "secret").unwrap();
+        let user = User::load(&path).unwrap();
+        assert_eq!(user.tokens.len(), 1);
+        assert_eq!(
+            user.tokens[0].token_hash,
+            hash_token("secret", issued.token.unwrap())
+        );
+
+        let content = RequestContent::RevokeToken(RevokeTokenRequest {
+            user_id: created.user_id.clone(),
+            token_id: user.tokens[0].id.clone(),
+        });
+        apply(&content, dir, "secret").unwrap();
+        assert!(User::load(&path).unwrap().tokens.is_empty());
+        let e = apply(&content, dir, "secret").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_failed_commit() {
+        // Not a git repository
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        let created = create(dir);
+        let path = dir.join(format!("{}.json", created.user_id));
+        let file_system = FsConfig {
+            users: temp.path().to_string_lossy().to_string(),
+            requests: temp.path().to_string_lossy().to_string(),
+            strict: false,
+            git: Some(GitConfig {
+                name: None,
+                email: None,
+                signing_key: None,
+            }),
+        };
+        let content = RequestContent::CreateUser(CreateUserRequest {
+            username: "other-user".to_string(),
+            email: "other-user@example.com".to_string(),
+            superuser: false,
+            acl: vec![],
+        });
+        let e = apply_and_commit(&content, &file_system, "secret", &Origin::local()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Backend);
+        assert_eq!(User::load_all(dir, true).unwrap().len(), 1);
+
+        let content = RequestContent::UpdateUser(UpdateUserRequest {
+            user_id: created.user_id.clone(),
+            username: None,
+            email: None,
+            superuser: Some(true),
+            acl: None,
+            renew_password: true,
+            renew_pubkey: false,
+            renew_totp: false,
+        });
+        assert!(apply_and_commit(&content, &file_system, "secret", &Origin::local()).is_err());
+        let content = RequestContent::DeleteUser(DeleteUserRequest {
+            user_id: created.user_id.clone(),
+        });
+        assert!(apply_and_commit(&content, &file_system, "secret", &Origin::local()).is_err());
+        let user = User::load(&path).unwrap();
+        assert!(!user.superuser);
+        assert!(user.verify("secret", created.password.unwrap()));
+    }
+
+    // Both requests start at once in each round.
+    fn apply_concurrently(
+        dir: &Path,
+        contents: [RequestContent; 2],
+    ) -> Vec<Result<Applied, Error>> {
+        let barrier = Barrier::new(2);
+        thread::scope(|scope| {
+            let threads: Vec<_> = contents
+                .iter()
+                .map(|content| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        apply(content, dir, "secret")
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        let created = create(dir);
+        let path = dir.join(format!("{}.json", created.user_id));
+
+        for i in 0..10 {
+            // Neither token is lost.
+            let issue = RequestContent::IssueToken(IssueTokenRequest {
+                user_id: created.user_id.clone(),
+                label: format!("ci {}", i),
+                acl: vec![],
+                expires_at: None,
+            });
+            for ret in apply_concurrently(dir, [issue.clone(), issue]) {
+                ret.unwrap();
+            }
+            assert_eq!(User::load(&path).unwrap().tokens.len(), 2 * (i + 1));
+
+            // Only one user gets the username.
+            let create = RequestContent::CreateUser(CreateUserRequest {
+                username: format!("user-{}", i),
+                email: format!("user-{}@example.com", i),
+                superuser: false,
+                acl: vec![],
+            });
+            let rets = apply_concurrently(dir, [create.clone(), create]);
+            assert_eq!(rets.iter().filter(|ret| ret.is_ok()).count(), 1);
+        }
+        assert_eq!(User::load_all(dir, true).unwrap().len(), 11);
+    }
+}
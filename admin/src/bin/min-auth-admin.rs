@@ -1,12 +1,22 @@
-use std::{env, net::SocketAddr, str::FromStr, sync::Arc};
+use std::{env, io, net::SocketAddr, str::FromStr, sync::Arc};
 
 use aes_gcm::{aead::OsRng, Aes256Gcm, KeyInit};
-use getopts::Options;
-use hyper::server::conn::http1;
-use hyper_util::rt::TokioIo;
-use log::error;
-use min_auth_common::{config::admin::AdminConfig, data::users::User, DynError};
-use tokio::{net::TcpListener, sync::RwLock, task::JoinSet};
+use getopts::{Options, ParsingStyle};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use log::{error, info};
+use min_auth_admin::{cli, service::Service, sync};
+use min_auth_common::{
+    config::admin::AdminConfig, data::users::User, tls::TlsTerminator, DynError,
+};
+use redis::Client as RedisClient;
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, RwLock},
+    task::JoinSet,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), DynError> {
@@ -15,6 +25,7 @@ async fn main() -> Result<(), DynError> {
     let args: Vec<String> = env::args().collect();
     let mut opts = Options::new();
     opts.optopt("c", "config", "path to a config file", "CONFIG");
+    opts.parsing_style(ParsingStyle::StopAtFirstFree);
     let matches = opts.parse(&args[1..])?;
     let config_path = match matches.opt_str("c") {
         Some(path) => path,
@@ -22,35 +33,71 @@ async fn main() -> Result<(), DynError> {
     };
 
     let config = AdminConfig::load(&config_path)?;
+    // Manage the files offline if a subcommand is given.
+    if !matches.free.is_empty() {
+        return cli::run(&config, &matches.free, &mut io::stdout());
+    }
     let addrs = config.expose.sockets.clone();
-    let users = User::load_all(&config.file_system.users)?;
+    // Refuse to start on corrupt data in strict mode.
+    let users = User::load_all(&config.file_system.users, config.file_system.strict)?;
+    info!("{} users were loaded.", users.len());
+    let redis = RedisClient::open(config.redis.session.as_str())?;
     let session_key = Aes256Gcm::generate_key(OsRng);
 
     let config = Arc::new(RwLock::new(config));
-    let users = Arc::new(RwLock::new(users));
+    let redis = Arc::new(Mutex::new(redis));
     let session_key = Arc::new(RwLock::new(session_key));
 
     let mut join_set: JoinSet<Result<(), DynError>> = JoinSet::new();
 
+    if let Some(drift) = config.read().await.drift.clone() {
+        join_set.spawn(sync::watch(Arc::clone(&config), drift));
+    }
+
     for addr in addrs {
         let config = Arc::clone(&config);
-        let users = Arc::clone(&users);
+        let redis = Arc::clone(&redis);
         let session_key = Arc::clone(&session_key);
+        // HTTP/1.1 and HTTP/2
+        let builder = Arc::new(auto::Builder::new(TokioExecutor::new()));
+        let tls = match addr.tls() {
+            Some(tls) => Some(Arc::new(TlsTerminator::load(tls)?)),
+            None => None,
+        };
 
         join_set.spawn(async move {
-            let addr = SocketAddr::from_str(addr.as_str())?;
+            let addr = SocketAddr::from_str(addr.address())?;
             let listener = TcpListener::bind(addr).await?;
-            let svc = AdminService {
+            let svc = Service {
                 config,
-                users,
+                redis,
                 session_key,
             };
             loop {
-                let (stream, _) = listener.accept().await?;
-                let io = TokioIo::new(stream);
+                let (stream, remote) = listener.accept().await?;
                 let svc_clone = svc.clone();
+                let tls = tls.clone();
+                let builder = Arc::clone(&builder);
                 tokio::task::spawn(async move {
-                    if let Err(err) = http1::Builder::new().serve_connection(io, svc_clone).await {
+                    let ret = match tls {
+                        Some(tls) => match tls.accept(stream).await {
+                            Ok(stream) => {
+                                builder
+                                    .serve_connection(TokioIo::new(stream), svc_clone)
+                                    .await
+                            }
+                            Err(e) => {
+                                error!("TLS handshake with {} failed: {}", remote, e);
+                                return;
+                            }
+                        },
+                        None => {
+                            builder
+                                .serve_connection(TokioIo::new(stream), svc_clone)
+                                .await
+                        }
+                    };
+                    if let Err(err) = ret {
                         error!("{:?}", err);
                     }
                 });
@@ -0,0 +1,497 @@
+use crate::{
+    apply::{apply_and_commit, directory, Applied},
+    git::Origin,
+    sync::{sync_all, SyncDiff},
+};
+use getopts::Options;
+use log::{error, info};
+use min_auth_common::{
+    config::admin::AdminConfig,
+    data::{
+        requests::{
+            CreateUserRequest, DeleteUserRequest, Request, RequestContent, UpdateUserRequest,
+        },
+        users::{AccessControl, AccessControlKind, User},
+        DataSaver,
+    },
+    error::{Error, ErrorKind},
+    DynError,
+};
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+pub const USAGE: &str = "\
+Commands:
+    user list
+    user show USER_ID
+    user add --username NAME --email EMAIL [--superuser] [--acl allow|deny:SERVICE]...
+    user update USER_ID [--username NAME] [--email EMAIL] [--superuser true|false]
+                [--acl allow|deny:SERVICE]... [--renew-password] [--renew-totp]
+    user delete USER_ID
+    request list
+    request show REQUEST_ID
+    request apply REQUEST_ID
+    request reject REQUEST_ID
+    sync [--dry-run]
+    verify [--repair]
+
+Generated passwords, TOTP secrets and tokens are printed once in JSON.
+Without a key_prefix, sync and verify only delete left over keys holding
+a min-auth record, leaving other keys in the auth Redis alone.";
+
+// Run a subcommand on the files in `file_system` without the server.
+pub fn run<W>(config: &AdminConfig, args: &[String], out: &mut W) -> Result<(), DynError>
+where
+    W: Write,
+{
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["user", "list"] => {
+            let mut users: Vec<User> =
+                User::load_all(&config.file_system.users, config.file_system.strict)?
+                    .into_values()
+                    .collect();
+            users.sort_by(|a, b| a.id.cmp(&b.id));
+            for user in users {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    user.id, user.username, user.email, user.superuser
+                )?;
+            }
+        }
+        ["user", "show", user_id] => {
+            let (_, user) = User::find(&config.file_system.users, user_id)?;
+            writeln!(out, "{}", serde_json::to_string_pretty(&user)?)?;
+        }
+        ["user", "add", rest @ ..] => {
+            let matches = parse(user_options(false), rest)?;
+            let content = RequestContent::CreateUser(CreateUserRequest {
+                username: required(&matches, "username")?,
+                email: required(&matches, "email")?,
+                superuser: matches.opt_present("superuser"),
+                acl: parse_acl(&matches.opt_strs("acl"))?,
+            });
+            validate(config, &content)?;
+            run_apply(config, &content, &Origin::local(), out)?;
+        }
+        ["user", "update", user_id, rest @ ..] => {
+            let matches = parse(user_options(true), rest)?;
+            let superuser = match matches.opt_str("superuser").as_deref() {
+                Some("true") => Some(true),
+                Some("false") => Some(false),
+                Some(value) => return Err(bad_request(format!("Invalid superuser: {}", value))),
+                None => None,
+            };
+            let acl = matches.opt_strs("acl");
+            let content = RequestContent::UpdateUser(UpdateUserRequest {
+                user_id: user_id.to_string(),
+                username: matches.opt_str("username"),
+                email: matches.opt_str("email"),
+                superuser,
+                acl: match acl.is_empty() {
+                    true => None,
+                    false => Some(parse_acl(&acl)?),
+                },
+                renew_password: matches.opt_present("renew-password"),
+                renew_pubkey: false,
+                renew_totp: matches.opt_present("renew-totp"),
+            });
+            validate(config, &content)?;
+            run_apply(config, &content, &Origin::local(), out)?;
+        }
+        ["user", "delete", user_id] => {
+            let content = RequestContent::DeleteUser(DeleteUserRequest {
+                user_id: user_id.to_string(),
+            });
+            apply_and_commit(&content, &config.file_system, "", &Origin::local())?;
+            info!("{} was deleted.", user_id);
+        }
+        ["request", "list"] => {
+            let mut requests =
+                Request::load_all(&config.file_system.requests, config.file_system.strict)?;
+            requests.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+            for request in requests {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    request.id,
+                    request.issuer,
+                    request.timestamp,
+                    request.content.kind()
+                )?;
+            }
+        }
+        ["request", "show", request_id] => {
+            let (_, request) = Request::find(&config.file_system.requests, request_id)?;
+            writeln!(out, "{}", serde_json::to_string_pretty(&request)?)?;
+        }
+        ["request", "apply", request_id] => {
+            let (path, request) = Request::find(&config.file_system.requests, request_id)?;
+            run_apply(config, &request.content, &Origin::from(&request), out)?;
+            Request::remove(path)?;
+            info!("Request {} by {} was applied.", request.id, request.issuer);
+        }
+        ["request", "reject", request_id] => {
+            let (path, request) = Request::find(&config.file_system.requests, request_id)?;
+            Request::remove(path)?;
+            info!("Request {} by {} was rejected.", request.id, request.issuer);
+        }
+        ["sync", rest @ ..] => {
+            let mut opts = Options::new();
+            opts.optflag("", "dry-run", "only report the differences");
+            let matches = parse(opts, rest)?;
+            run_sync(config, matches.opt_present("dry-run"), false, out)?;
+        }
+        ["verify", rest @ ..] => {
+            let mut opts = Options::new();
+            opts.optflag("", "repair", "overwrite drifted records");
+            let repair = parse(opts, rest)?.opt_present("repair");
+            run_sync(config, !repair, !repair, out)?;
+        }
+        _ => return Err(bad_request(USAGE)),
+    }
+    Ok(())
+}
+
+// Reject a request before anything is generated for it. It is checked again
+// when applied, as the directory may have changed in between.
+fn validate(config: &AdminConfig, content: &RequestContent) -> Result<(), DynError> {
+    let users = directory(Path::new(&config.file_system.users))?;
+    Ok(content.validate(&users)?)
+}
+
+fn run_apply<W>(
+    config: &AdminConfig,
+    content: &RequestContent,
+    origin: &Origin,
+    out: &mut W,
+) -> Result<(), DynError>
+where
+    W: Write,
+{
+    let applied: Applied = apply_and_commit(
+        content,
+        &config.file_system,
+        &config.security.password_secret,
+        origin,
+    )?;
+    writeln!(out, "{}", serde_json::to_string_pretty(&applied)?)?;
+    Ok(())
+}
+
+// With `strict`, drift is an error even if nothing failed.
+fn run_sync<W>(
+    config: &AdminConfig,
+    dry_run: bool,
+    strict: bool,
+    out: &mut W,
+) -> Result<(), DynError>
+where
+    W: Write,
+{
+    let mut diffs = BTreeMap::new();
+    let mut failed = 0;
+    for (name, ret) in sync_all(config, dry_run)? {
+        match ret {
+            Ok(diff) => {
+                info!("{}: {}.", name, diff);
+                diffs.insert(name, diff);
+            }
+            Err(e) => {
+                error!("{}: {} ({})", name, e, e.code());
+                failed += 1;
+            }
+        }
+    }
+    writeln!(out, "{}", serde_json::to_string_pretty(&diffs)?)?;
+    sync_status(&diffs, failed, strict)
+}
+
+// The exit status of `sync` and `verify`
+fn sync_status(
+    diffs: &BTreeMap<String, SyncDiff>,
+    failed: usize,
+    strict: bool,
+) -> Result<(), DynError> {
+    if failed > 0 {
+        return Err(format!("{} Redis instances failed to sync.", failed).into());
+    }
+    let drifted = diffs.values().filter(|diff| !diff.is_empty()).count();
+    match strict && drifted > 0 {
+        true => Err(format!("{} Redis instances have drifted.", drifted).into()),
+        false => Ok(()),
+    }
+}
+
+fn user_options(update: bool) -> Options {
+    let mut opts = Options::new();
+    opts.optopt("", "username", "user name", "NAME");
+    opts.optopt("", "email", "email address", "EMAIL");
+    match update {
+        true => opts.optopt("", "superuser", "grant or revoke superuser", "true|false"),
+        false => opts.optflag("", "superuser", "grant superuser"),
+    };
+    opts.optmulti("", "acl", "access control in order", "allow|deny:SERVICE");
+    opts.optflag("", "renew-password", "generate a new password");
+    opts.optflag("", "renew-totp", "enroll a new TOTP secret");
+    opts
+}
+
+fn parse(opts: Options, args: &[&str]) -> Result<getopts::Matches, DynError> {
+    let matches = opts.parse(args)?;
+    match matches.free.first() {
+        Some(arg) => Err(bad_request(format!("Unexpected argument: {}", arg))),
+        None => Ok(matches),
+    }
+}
+
+fn required(matches: &getopts::Matches, name: &str) -> Result<String, DynError> {
+    match matches.opt_str(name) {
+        Some(value) => Ok(value),
+        None => Err(bad_request(format!("--{} is required.", name))),
+    }
+}
+
+// Entries are evaluated in the given order, e.g. "allow:service 1" "deny:*"
+fn parse_acl(values: &[String]) -> Result<Vec<AccessControl>, DynError> {
+    values
+        .iter()
+        .map(|value| {
+            let (control, service) = match value.split_once(':') {
+                Some((control, service)) if !service.is_empty() => (control, service),
+                _ => return Err(bad_request(format!("Invalid ACL: {}", value))),
+            };
+            let control = match control.to_ascii_lowercase().as_str() {
+                "allow" => AccessControlKind::Allow,
+                "deny" => AccessControlKind::Deny,
+                _ => return Err(bad_request(format!("Invalid ACL: {}", value))),
+            };
+            Ok(AccessControl {
+                control,
+                service: service.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn bad_request<T>(message: T) -> DynError
+where
+    T: std::fmt::Display,
+{
+    Box::new(Error::new(ErrorKind::BadRequest, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use min_auth_common::config::admin::{
+        ExposeConfig, FsConfig, GitConfig, RedisConfig, SecurityConfig,
+    };
+    use serde_json::Value;
+    use std::{
+        fs::{create_dir_all, File},
+        path::Path,
+    };
+    use tempfile::{tempdir, TempDir};
+
+    // The directory is removed when dropped.
+    fn config() -> (TempDir, AdminConfig) {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        create_dir_all(dir.join("users")).unwrap();
+        create_dir_all(dir.join("requests")).unwrap();
+        let config = AdminConfig {
+            expose: ExposeConfig { sockets: vec![] },
+            redis: RedisConfig {
+                session: "redis://127.0.0.1/0".to_string(),
+                auth: vec![],
+                key_prefix: String::new(),
+                encoding: Default::default(),
+            },
+            security: SecurityConfig {
+                password_secret: "secret".to_string(),
+            },
+            file_system: FsConfig {
+                users: dir.join("users").to_string_lossy().to_string(),
+                requests: dir.join("requests").to_string_lossy().to_string(),
+                strict: false,
+                git: None,
+            },
+            webauthn: None,
+            drift: None,
+        };
+        (temp, config)
+    }
+
+    fn run_str(config: &AdminConfig, args: &[&str]) -> Result<String, DynError> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut out = vec![];
+        run(config, &args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_user() {
+        let (_temp, config) = config();
+        let out = run_str(
+            &config,
+            &[
+                "user",
+                "add",
+                "--username",
+                "alice",
+                "--email",
+                "alice@example.com",
+                "--acl",
+                "allow:service 1",
+                "--acl",
+                "deny:*",
+            ],
+        )
+        .unwrap();
+        let added: Value = serde_json::from_str(&out).unwrap();
+        let user_id = added["user_id"].as_str().unwrap();
+        assert!(added["password"].is_string());
+
+        let (_, user) = User::find(&config.file_system.users, user_id).unwrap();
+        assert!(!user.superuser);
+        assert_eq!(user.acl.len(), 2);
+        assert_eq!(user.acl[1].control, AccessControlKind::Deny);
+        assert!(user.verify("secret", added["password"].as_str().unwrap()));
+
+        let out = run_str(&config, &["user", "list"]).unwrap();
+        assert_eq!(
+            out,
+            format!("{}\talice\talice@example.com\tfalse\n", user_id)
+        );
+
+        let out = run_str(
+            &config,
+            &[
+                "user",
+                "update",
+                user_id,
+                "--superuser",
+                "true",
+                "--renew-totp",
+            ],
+        )
+        .unwrap();
+        let updated: Value = serde_json::from_str(&out).unwrap();
+        assert!(updated["password"].is_null());
+        let (_, user) = User::find(&config.file_system.users, user_id).unwrap();
+        assert!(user.superuser);
+        assert_eq!(user.totp_secret.as_deref(), updated["totp_secret"].as_str());
+
+        // Checked before anything is generated
+        let e = run_str(
+            &config,
+            &["user", "add", "--username", "ALICE", "--email", "a@b"],
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("is taken by"));
+        assert!(e.to_string().contains("invalid domain"));
+        let e = run_str(&config, &["user", "update", user_id, "--email", "no-at"]).unwrap_err();
+        assert!(e.to_string().contains("has no @"));
+        assert_eq!(
+            run_str(&config, &["user", "list"]).unwrap().lines().count(),
+            1
+        );
+
+        run_str(&config, &["user", "delete", user_id]).unwrap();
+        assert!(run_str(&config, &["user", "show", user_id]).is_err());
+
+        // Missing or invalid options
+        assert!(run_str(&config, &["user", "add", "--username", "bob"]).is_err());
+        assert!(run_str(
+            &config,
+            &[
+                "user",
+                "add",
+                "--username",
+                "bob",
+                "--email",
+                "b@example.com",
+                "--acl",
+                "maybe:x"
+            ]
+        )
+        .is_err());
+        assert!(run_str(&config, &["user"]).is_err());
+    }
+
+    #[test]
+    fn test_request() {
+        let (_temp, config) = config();
+        let request = Request {
+            id: "req-1".to_string(),
+            issuer: "admin".to_string(),
+            timestamp: "2024-01-01 12:34:56.789".to_string(),
+            content: RequestContent::CreateUser(CreateUserRequest {
+                username: "carol".to_string(),
+                email: "carol@example.com".to_string(),
+                superuser: false,
+                acl: vec![],
+            }),
+            rand: 1,
+        };
+        let path = Path::new(&config.file_system.requests).join("req-1.json");
+        serde_json::to_writer(File::create(&path).unwrap(), &request).unwrap();
+
+        let out = run_str(&config, &["request", "list"]).unwrap();
+        assert_eq!(out, "req-1\tadmin\t2024-01-01 12:34:56.789\tCreateUser\n");
+        assert!(run_str(&config, &["request", "show", "req-1"]).is_ok());
+
+        let out = run_str(&config, &["request", "apply", "req-1"]).unwrap();
+        let applied: Value = serde_json::from_str(&out).unwrap();
+        assert!(!path.exists());
+        let (_, user) = User::find(
+            &config.file_system.users,
+            applied["user_id"].as_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(user.username, "carol");
+
+        serde_json::to_writer(File::create(&path).unwrap(), &request).unwrap();
+        run_str(&config, &["request", "reject", "req-1"]).unwrap();
+        assert!(!path.exists());
+        assert!(run_str(&config, &["request", "apply", "req-1"]).is_err());
+
+        // Kept for another try if it cannot be committed
+        let (_temp, mut uncommitted) = self::config();
+        uncommitted.file_system.git = Some(GitConfig {
+            name: None,
+            email: None,
+            signing_key: None,
+        });
+        let path = Path::new(&uncommitted.file_system.requests).join("req-1.json");
+        serde_json::to_writer(File::create(&path).unwrap(), &request).unwrap();
+        assert!(run_str(&uncommitted, &["request", "apply", "req-1"]).is_err());
+        assert!(path.exists());
+        assert!(run_str(&uncommitted, &["user", "list"]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify() {
+        let (_temp, mut config) = config();
+        assert!(run_str(&config, &["verify"]).is_ok());
+        assert!(run_str(&config, &["verify", "--repair"]).is_ok());
+        config.redis.auth = vec!["redis://127.0.0.1:1/0".to_string()];
+        assert!(run_str(&config, &["verify"]).is_err());
+        assert!(run_str(&config, &["verify", "--repair"]).is_err());
+
+        // Drift fails a check but not a repair.
+        let drifted = BTreeMap::from([(
+            "redis_auth_0".to_string(),
+            SyncDiff {
+                missing: vec!["Foo1".to_string()],
+                ..Default::default()
+            },
+        )]);
+        assert!(sync_status(&drifted, 0, true).is_err());
+        assert!(sync_status(&drifted, 0, false).is_ok());
+        assert!(sync_status(&drifted, 1, false).is_err());
+        let clean = BTreeMap::from([("redis_auth_0".to_string(), SyncDiff::default())]);
+        assert!(sync_status(&clean, 0, true).is_ok());
+    }
+}
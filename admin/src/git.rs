@@ -0,0 +1,229 @@
+use chrono::Utc;
+use min_auth_common::{
+    config::admin::GitConfig,
+    data::requests::Request,
+    error::{Error, ErrorKind},
+};
+use serde::Serialize;
+use std::{env, path::Path, process::Command};
+
+// Separates the fields and the commits of `git log`
+const FIELD_SEP: char = '\x1f';
+const COMMIT_SEP: char = '\x1e';
+
+// Where a change came from, recorded in the trailers of its commit
+#[derive(Debug, Clone)]
+pub struct Origin {
+    pub request_id: Option<String>,
+    pub issuer: String,
+    pub timestamp: String,
+}
+
+impl From<&Request> for Origin {
+    fn from(request: &Request) -> Self {
+        Self {
+            request_id: Some(request.id.clone()),
+            issuer: request.issuer.clone(),
+            timestamp: request.timestamp.clone(),
+        }
+    }
+}
+
+impl Origin {
+    // A change made on the command line without a request
+    pub fn local() -> Self {
+        Self {
+            request_id: None,
+            issuer: env::var("USER").unwrap_or_else(|_| "cli".to_string()),
+            timestamp: Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+        }
+    }
+}
+
+// A commit changing a user
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    pub commit: String,
+    pub committed_at: String,
+    pub summary: String,
+    pub request_id: Option<String>,
+    pub issuer: Option<String>,
+    pub timestamp: Option<String>,
+}
+
+// Commit the JSON file of a user only, leaving other changes in the directory alone.
+// Callers serialize commits, as git fails rather than waits on a locked index.
+pub fn commit<P>(
+    users_dir: P,
+    config: &GitConfig,
+    user_id: &str,
+    summary: &str,
+    origin: &Origin,
+) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let users_dir = users_dir.as_ref();
+    let spec = pathspec(user_id)?;
+    git(users_dir, &["add", "--all", "--", &spec])?;
+    if git(users_dir, &["status", "--porcelain", "--", &spec])?.is_empty() {
+        return Ok(());
+    }
+
+    let mut message = format!("{}\n\n", summary);
+    if let Some(request_id) = &origin.request_id {
+        message += &format!("Request: {}\n", request_id);
+    }
+    message += &format!(
+        "Issuer: {}\nTimestamp: {}\n",
+        origin.issuer, origin.timestamp
+    );
+
+    let mut args = vec![];
+    if let Some(name) = &config.name {
+        args.extend(["-c".to_string(), format!("user.name={}", name)]);
+    }
+    if let Some(email) = &config.email {
+        args.extend(["-c".to_string(), format!("user.email={}", email)]);
+    }
+    args.extend(["commit".to_string(), "--message".to_string(), message]);
+    if let Some(key) = &config.signing_key {
+        args.push(format!("--gpg-sign={}", key));
+    }
+    args.extend(["--".to_string(), spec]);
+    git(users_dir, &args)?;
+    Ok(())
+}
+
+// The commits changing a user, newest first
+pub fn history<P>(users_dir: P, user_id: &str) -> Result<Vec<Change>, Error>
+where
+    P: AsRef<Path>,
+{
+    let format = format!("--format=%H{}%cI{}%B{}", FIELD_SEP, FIELD_SEP, COMMIT_SEP);
+    let log = git(
+        users_dir.as_ref(),
+        &["log", &format, "--", &pathspec(user_id)?],
+    )?;
+    Ok(log
+        .split(COMMIT_SEP)
+        .filter_map(|commit| {
+            let mut fields = commit.trim().splitn(3, FIELD_SEP);
+            let (commit, committed_at, message) = (fields.next()?, fields.next()?, fields.next()?);
+            let mut lines = message.lines();
+            let mut change = Change {
+                commit: commit.to_string(),
+                committed_at: committed_at.to_string(),
+                summary: lines.next().unwrap_or_default().to_string(),
+                request_id: None,
+                issuer: None,
+                timestamp: None,
+            };
+            for line in lines {
+                match line.split_once(": ") {
+                    Some(("Request", value)) => change.request_id = Some(value.to_string()),
+                    Some(("Issuer", value)) => change.issuer = Some(value.to_string()),
+                    Some(("Timestamp", value)) => change.timestamp = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            Some(change)
+        })
+        .collect())
+}
+
+// The file of a user anywhere under the directory
+fn pathspec(user_id: &str) -> Result<String, Error> {
+    if user_id.is_empty()
+        || !user_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::new(
+            ErrorKind::BadRequest,
+            format!("Invalid user ID: {}", user_id),
+        ));
+    }
+    Ok(format!(":(glob)**/{}.json", user_id))
+}
+
+fn git<S>(dir: &Path, args: &[S]) -> Result<String, Error>
+where
+    S: AsRef<str>,
+{
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args.iter().map(AsRef::as_ref))
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::new(
+            ErrorKind::Backend,
+            format!(
+                "git failed in {}: {}",
+                dir.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_file, write};
+    use tempfile::{tempdir, TempDir};
+
+    fn repository() -> TempDir {
+        let dir = tempdir().unwrap();
+        create_dir_all(dir.path().join("sub")).unwrap();
+        git(dir.path(), &["init", "--quiet"]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_commit_and_history() {
+        let temp = repository();
+        let dir = temp.path();
+        let config = GitConfig {
+            name: Some("min-auth".to_string()),
+            email: Some("min-auth@example.com".to_string()),
+            signing_key: None,
+        };
+        let origin = Origin {
+            request_id: Some("r1".to_string()),
+            issuer: "admin".to_string(),
+            timestamp: "2024-01-01 00:00:00.000".to_string(),
+        };
+
+        write(dir.join("sub/u1.json"), "{}").unwrap();
+        write(dir.join("u2.json"), "{}").unwrap();
+        commit(dir, &config, "u1", "CreateUser u1", &origin).unwrap();
+        // Nothing changed
+        commit(dir, &config, "u1", "UpdateUser u1", &origin).unwrap();
+        remove_file(dir.join("sub/u1.json")).unwrap();
+        let origin = Origin {
+            request_id: None,
+            ..origin
+        };
+        commit(dir, &config, "u1", "DeleteUser u1", &origin).unwrap();
+
+        let changes = history(dir, "u1").unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].summary, "DeleteUser u1");
+        assert_eq!(changes[0].request_id, None);
+        assert_eq!(changes[1].summary, "CreateUser u1");
+        assert_eq!(changes[1].request_id, Some("r1".to_string()));
+        assert_eq!(changes[1].issuer, Some("admin".to_string()));
+        assert_eq!(
+            changes[1].timestamp,
+            Some("2024-01-01 00:00:00.000".to_string())
+        );
+        // Other users are not committed.
+        assert!(history(dir, "u2").unwrap().is_empty());
+
+        let e = history(dir, "*").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadRequest);
+    }
+}
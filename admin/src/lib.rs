@@ -1,2 +1,7 @@
+pub mod apply;
+pub mod cli;
+pub mod git;
 pub mod service;
 pub mod session;
+pub mod sync;
+pub mod webauthn;
@@ -1,15 +1,27 @@
 use aes_gcm::{Aes256Gcm, Key as AesKey};
 use bytes::Bytes;
-use http_body_util::Full;
-use hyper::{body::Incoming, Method, Request, Response};
-use min_auth_common::{config::admin::AdminConfig, data::users::User, error::Error, DynError};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{body::Incoming, header, Method, Request, Response, StatusCode};
+use log::error;
+use min_auth_common::{
+    config::admin::AdminConfig,
+    data::users::User,
+    error::{Error, ErrorKind},
+    DynError,
+};
 use redis::Client as RedisClient;
-use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, future::Future, path::Path, pin::Pin, sync::Arc};
 use tokio::sync::{Mutex, RwLock};
 
+mod health;
+mod history;
 mod login;
 mod update;
 mod users;
+mod webauthn;
+
+const MAX_BODY_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct Service {
@@ -33,16 +45,73 @@ impl hyper::service::Service<Request<Incoming>> for Service {
             let path = req.uri().path();
             match (method, path) {
                 (&Method::POST, "/login") => login::login(req, &redis, &session_key, &config).await,
+                (&Method::POST, "/webauthn/login") => {
+                    webauthn::login_options(req, &redis, &config).await
+                }
+                (&Method::POST, "/webauthn/register") => {
+                    webauthn::register_options(req, &redis, &session_key, &config).await
+                }
+                (&Method::POST, "/webauthn/register/finish") => {
+                    webauthn::register(req, &redis, &session_key, &config).await
+                }
                 (&Method::GET, "/users") => {
                     users::get_users(req, &redis, &session_key, &config).await
                 }
                 (&Method::POST, "/update") => {
                     update::update(req, &redis, &session_key, &config).await
                 }
+                (&Method::POST, "/history") => history::history(req, &session_key, &config).await,
+                (&Method::GET, "/healthz") => health::healthz(),
+                (&Method::GET, "/readyz") => health::readyz(&redis, &session_key, &config).await,
                 (method, path) => {
-                    Err(Error::new(format!("Illegal request ({} {})", method, path)).into())
+                    let e = Error::new(
+                        ErrorKind::NotFound,
+                        format!("Illegal request ({} {})", method, path),
+                    );
+                    error!("{}", e);
+                    Ok(Response::builder()
+                        .status(e.status())
+                        .body("".to_string().into_bytes().into())?)
                 }
             }
         })
     }
 }
+
+pub(crate) async fn read_json<T>(req: Request<Incoming>) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return Err(Error::new(ErrorKind::BadRequest, e)),
+    };
+    serde_json::from_slice(&body).map_err(|e| Error::new(ErrorKind::BadRequest, e))
+}
+
+pub(crate) fn json_response<T>(
+    status: StatusCode,
+    value: &T,
+) -> Result<Response<Full<Bytes>>, Error>
+where
+    T: Serialize,
+{
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(value)?.into())?)
+}
+
+pub(crate) fn find_user<P>(users_dir: P, user_id: &str) -> Result<User, Error>
+where
+    P: AsRef<Path>,
+{
+    let users: HashMap<String, User> = User::load_all(users_dir, false)?;
+    match users.into_values().find(|user| user.id == user_id) {
+        Some(user) => Ok(user),
+        None => Err(Error::new(
+            ErrorKind::UnknownUser,
+            format!("{} was not found.", user_id),
+        )),
+    }
+}
@@ -0,0 +1,71 @@
+use aes_gcm::{Aes256Gcm, Key as AesKey};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::Response;
+use min_auth_common::{
+    config::admin::AdminConfig,
+    health::{check_redis, health, ComponentHealth, HealthReport},
+    DynError,
+};
+use redis::Client as RedisClient;
+use std::{path::Path, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+
+pub(crate) fn healthz() -> Result<Response<Full<Bytes>>, DynError> {
+    Ok(health(&HealthReport::new())?)
+}
+
+pub(crate) async fn readyz(
+    redis: &Arc<Mutex<RedisClient>>,
+    session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    let config = config.read().await.clone();
+    let mut report = HealthReport::new();
+
+    // The config has been loaded once the service is running.
+    report.add("config", ComponentHealth::ok());
+
+    report.add(
+        "password_secret",
+        if config.security.password_secret.is_empty() {
+            ComponentHealth::fail("No password secret is configured.")
+        } else {
+            ComponentHealth::ok()
+        },
+    );
+
+    report.add(
+        "session_key",
+        if session_key.read().await.iter().all(|x| *x == 0) {
+            ComponentHealth::fail("No session key is generated.")
+        } else {
+            ComponentHealth::ok()
+        },
+    );
+
+    report.add("users", check_dir(&config.file_system.users));
+    report.add("requests", check_dir(&config.file_system.requests));
+
+    let redis = redis.lock().await.clone();
+    report.add("redis_session", check_redis(&redis).await);
+
+    // URIs may hold passwords, so name the components by their indices.
+    for (i, uri) in config.redis.auth.iter().enumerate() {
+        let health = match RedisClient::open(uri.as_str()) {
+            Ok(redis) => check_redis(&redis).await,
+            Err(e) => ComponentHealth::fail(e),
+        };
+        report.add(format!("redis_auth_{}", i), health);
+    }
+
+    Ok(health(&report)?)
+}
+
+fn check_dir(path: &str) -> ComponentHealth {
+    if Path::new(path).is_dir() {
+        ComponentHealth::ok()
+    } else {
+        ComponentHealth::fail(format!("{} is not a directory.", path))
+    }
+}
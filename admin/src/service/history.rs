@@ -0,0 +1,75 @@
+use super::{find_user, json_response, read_json};
+use crate::{git, session::AdminSession};
+use aes_gcm::{Aes256Gcm, Key as AesKey};
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::Full;
+use hyper::{body::Incoming, Request, Response, StatusCode};
+use log::error;
+use min_auth_common::{
+    config::admin::AdminConfig,
+    error::{Error, ErrorKind},
+    DynError,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::{sync::RwLock, task::spawn_blocking};
+
+#[derive(Deserialize)]
+struct HistoryRequest {
+    user_id: String,
+}
+
+pub(crate) async fn history(
+    req: Request<Incoming>,
+    session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    match history_body(req, session_key, config).await {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            error!("{} ({})", e, e.code());
+            Ok(Response::builder()
+                .status(e.status())
+                .body("".to_string().into_bytes().into())?)
+        }
+    }
+}
+
+// Users can see their own changes, and superusers those of anyone.
+async fn history_body(
+    req: Request<Incoming>,
+    session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, Error> {
+    let config = config.read().await.clone();
+    if config.file_system.git.is_none() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "The user directory is not a git repository.",
+        ));
+    }
+    let session = AdminSession::find(
+        req.headers(),
+        &*session_key.read().await,
+        Utc::now().timestamp(),
+    )?;
+    let history: HistoryRequest = read_json(req).await?;
+    if history.user_id != session.user_id
+        && !find_user(&config.file_system.users, &session.user_id)?.superuser
+    {
+        return Err(Error::new(
+            ErrorKind::ServiceDenied,
+            format!(
+                "{} is not allowed to see the history of {}.",
+                session.user_id, history.user_id
+            ),
+        ));
+    }
+
+    // Deleted users have a history too, so the directory is not searched.
+    let changes = spawn_blocking(move || git::history(&config.file_system.users, &history.user_id))
+        .await
+        .map_err(|e| Error::new(ErrorKind::Internal, e))??;
+    json_response(StatusCode::OK, &changes)
+}
@@ -1,13 +1,29 @@
+use super::{find_user, read_json, webauthn::verify_assertion};
+use crate::{session::AdminSession, webauthn::AssertionResponse};
 use aes_gcm::{Aes256Gcm, Key as AesKey};
 use bytes::Bytes;
+use chrono::Utc;
 use http_body_util::Full;
-use hyper::{body::Incoming, Request, Response, StatusCode};
-use log::error;
-use min_auth_common::{config::admin::AdminConfig, DynError};
+use hyper::{body::Incoming, header, Request, Response, StatusCode};
+use log::{error, info};
+use min_auth_common::{
+    config::admin::AdminConfig,
+    error::{Error, ErrorKind},
+    DynError,
+};
 use redis::Client as RedisClient;
+use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock};
 
+#[derive(Deserialize)]
+struct LoginRequest {
+    user_id: String,
+    password: Option<String>,
+    // An assertion for the challenge from /webauthn/login
+    webauthn: Option<AssertionResponse>,
+}
+
 pub(crate) async fn login(
     req: Request<Incoming>,
     redis: &Arc<Mutex<RedisClient>>,
@@ -17,9 +33,9 @@ pub(crate) async fn login(
     match login_body(req, redis, session_key, config).await {
         Ok(res) => Ok(res),
         Err(e) => {
-            error!("{}", e);
+            error!("{} ({})", e, e.code());
             Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .status(e.status())
                 .body("".to_string().into_bytes().into())?)
         }
     }
@@ -30,6 +46,57 @@ async fn login_body(
     redis: &Arc<Mutex<RedisClient>>,
     session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
     config: &Arc<RwLock<AdminConfig>>,
-) -> Result<Response<Full<Bytes>>, DynError> {
-    todo!()
+) -> Result<Response<Full<Bytes>>, Error> {
+    let config = config.read().await.clone();
+    let login: LoginRequest = read_json(req).await?;
+    let user = find_user(&config.file_system.users, &login.user_id)?;
+
+    // Users with a registered credential always use it.
+    let webauthn = match (&config.webauthn, &login.webauthn) {
+        (Some(webauthn), Some(assertion)) => {
+            verify_assertion(webauthn, &user, assertion, redis).await?;
+            true
+        }
+        (Some(_), None) if !user.webauthn.is_empty() => {
+            return Err(Error::new(
+                ErrorKind::WebAuthnRequired,
+                format!("No WebAuthn assertion was given for {}.", user.id),
+            ))
+        }
+        _ => false,
+    };
+
+    // A passkey replaces the password only for superusers.
+    let passwordless = webauthn
+        && user.superuser
+        && config
+            .webauthn
+            .as_ref()
+            .map(|webauthn| webauthn.passwordless)
+            .unwrap_or(false);
+    match &login.password {
+        Some(password) => {
+            if !user.verify(&config.security.password_secret, password) {
+                return Err(Error::new(
+                    ErrorKind::BadPassword,
+                    format!("Invalid password for {}.", user.id),
+                ));
+            }
+        }
+        None if passwordless => (),
+        None => {
+            return Err(Error::new(
+                ErrorKind::NoHeader,
+                format!("No password was given for {}.", user.id),
+            ))
+        }
+    }
+
+    let session = AdminSession::new(&user.id, webauthn, Utc::now().timestamp());
+    let value = session.seal(&*session_key.read().await)?;
+    info!("{} logged in (WebAuthn: {}).", user.id, webauthn);
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::SET_COOKIE, AdminSession::cookie(&value))
+        .body("".to_string().into_bytes().into())?)
 }
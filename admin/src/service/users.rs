@@ -1,9 +1,9 @@
 use aes_gcm::{Aes256Gcm, Key as AesKey};
 use bytes::Bytes;
 use http_body_util::Full;
-use hyper::{body::Incoming, Request, Response, StatusCode};
+use hyper::{body::Incoming, Request, Response};
 use log::error;
-use min_auth_common::{config::admin::AdminConfig, DynError};
+use min_auth_common::{config::admin::AdminConfig, error::Error, DynError};
 use redis::Client as RedisClient;
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock};
@@ -17,9 +17,9 @@ pub(crate) async fn get_users(
     match get_users_body(req, redis, session_key, config).await {
         Ok(res) => Ok(res),
         Err(e) => {
-            error!("{}", e);
+            error!("{} ({})", e, e.code());
             Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .status(e.status())
                 .body("".to_string().into_bytes().into())?)
         }
     }
@@ -30,6 +30,6 @@ async fn get_users_body(
     redis: &Arc<Mutex<RedisClient>>,
     session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
     config: &Arc<RwLock<AdminConfig>>,
-) -> Result<Response<Full<Bytes>>, DynError> {
+) -> Result<Response<Full<Bytes>>, Error> {
     todo!()
 }
@@ -0,0 +1,299 @@
+use super::{find_user, json_response, read_json};
+use crate::{
+    session::AdminSession,
+    webauthn::{new_challenge, AssertionResponse, RegistrationResponse, RelyingParty},
+};
+use aes_gcm::{Aes256Gcm, Key as AesKey};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::Full;
+use hyper::{body::Incoming, Request, Response, StatusCode};
+use log::{error, info};
+use min_auth_common::{
+    config::admin::{AdminConfig, WebAuthnConfig},
+    data::{
+        requests::{RegisterWebAuthnRequest, Request as DataRequest, RequestContent},
+        users::User,
+        webauthn::{COSE_EDDSA, COSE_ES256},
+        DataSaver,
+    },
+    error::{Error, ErrorKind},
+    utils::genid::genid,
+    DynError,
+};
+use rand::{thread_rng, Rng};
+use redis::{AsyncCommands, Client as RedisClient};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+
+// Seconds to wait for the authenticator
+const CHALLENGE_TTL: u64 = 5 * 60;
+const CHALLENGE_KEY_PREFIX: &str = "webauthn-challenge:";
+const COUNT_KEY_PREFIX: &str = "webauthn-count:";
+
+#[derive(Deserialize)]
+struct LoginOptionsRequest {
+    user_id: String,
+}
+
+// Options for navigator.credentials.get()
+#[derive(Serialize)]
+struct LoginOptions {
+    challenge: String,
+    rp_id: String,
+    allow_credentials: Vec<String>,
+    user_verification: &'static str,
+}
+
+// Options for navigator.credentials.create()
+#[derive(Serialize)]
+struct RegisterOptions {
+    challenge: String,
+    rp_id: String,
+    rp_name: String,
+    // The user handle in base64url
+    user_id: String,
+    user_name: String,
+    algorithms: Vec<i64>,
+    exclude_credentials: Vec<String>,
+    user_verification: &'static str,
+    attestation: &'static str,
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    label: String,
+    response: RegistrationResponse,
+}
+
+pub(crate) async fn login_options(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    match login_options_body(req, redis, config).await {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            error!("{} ({})", e, e.code());
+            Ok(Response::builder()
+                .status(e.status())
+                .body("".to_string().into_bytes().into())?)
+        }
+    }
+}
+
+async fn login_options_body(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, Error> {
+    let config = config.read().await.clone();
+    let webauthn = enabled(&config)?;
+    let options: LoginOptionsRequest = read_json(req).await?;
+    let user = find_user(&config.file_system.users, &options.user_id)?;
+
+    let challenge = new_challenge();
+    store_challenge("login", &user.id, &challenge, redis).await?;
+    json_response(
+        StatusCode::OK,
+        &LoginOptions {
+            challenge,
+            rp_id: webauthn.rp_id.clone(),
+            allow_credentials: user.webauthn.iter().map(|c| c.id.clone()).collect(),
+            user_verification: user_verification(webauthn),
+        },
+    )
+}
+
+pub(crate) async fn register_options(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    match register_options_body(req, redis, session_key, config).await {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            error!("{} ({})", e, e.code());
+            Ok(Response::builder()
+                .status(e.status())
+                .body("".to_string().into_bytes().into())?)
+        }
+    }
+}
+
+async fn register_options_body(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, Error> {
+    let config = config.read().await.clone();
+    let webauthn = enabled(&config)?;
+    let session = AdminSession::find(
+        req.headers(),
+        &*session_key.read().await,
+        Utc::now().timestamp(),
+    )?;
+    let user = find_user(&config.file_system.users, &session.user_id)?;
+
+    let challenge = new_challenge();
+    store_challenge("register", &user.id, &challenge, redis).await?;
+    json_response(
+        StatusCode::OK,
+        &RegisterOptions {
+            challenge,
+            rp_id: webauthn.rp_id.clone(),
+            rp_name: webauthn.rp_name.clone(),
+            user_id: URL_SAFE_NO_PAD.encode(&user.id),
+            user_name: user.username.clone(),
+            algorithms: vec![COSE_ES256, COSE_EDDSA],
+            exclude_credentials: user.webauthn.iter().map(|c| c.id.clone()).collect(),
+            user_verification: user_verification(webauthn),
+            attestation: "none",
+        },
+    )
+}
+
+pub(crate) async fn register(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    match register_body(req, redis, session_key, config).await {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            error!("{} ({})", e, e.code());
+            Ok(Response::builder()
+                .status(e.status())
+                .body("".to_string().into_bytes().into())?)
+        }
+    }
+}
+
+async fn register_body(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    session_key: &Arc<RwLock<AesKey<Aes256Gcm>>>,
+    config: &Arc<RwLock<AdminConfig>>,
+) -> Result<Response<Full<Bytes>>, Error> {
+    let config = config.read().await.clone();
+    let webauthn = enabled(&config)?;
+    let session = AdminSession::find(
+        req.headers(),
+        &*session_key.read().await,
+        Utc::now().timestamp(),
+    )?;
+    let register: RegisterRequest = read_json(req).await?;
+
+    let challenge = take_challenge("register", &session.user_id, redis).await?;
+    let credential =
+        RelyingParty::new(webauthn).register(&challenge, &register.label, &register.response)?;
+
+    // The credential is added to the user JSON like any other change.
+    let request = DataRequest {
+        id: genid(),
+        issuer: session.user_id.clone(),
+        timestamp: Utc::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+        content: RequestContent::RegisterWebAuthn(RegisterWebAuthnRequest {
+            user_id: session.user_id.clone(),
+            credential: credential.clone(),
+        }),
+        rand: thread_rng().gen(),
+    };
+    let path = Path::new(&config.file_system.requests).join(format!("{}.json", request.id));
+    request.save_new(path)?;
+    info!(
+        "WebAuthn credential {} of {} was requested in {}.",
+        credential.id, session.user_id, request.id
+    );
+
+    json_response(StatusCode::CREATED, &credential)
+}
+
+// Verify an assertion for the pending login challenge of the user.
+pub(crate) async fn verify_assertion(
+    config: &WebAuthnConfig,
+    user: &User,
+    assertion: &AssertionResponse,
+    redis: &Arc<Mutex<RedisClient>>,
+) -> Result<(), Error> {
+    let credential = match user.webauthn.iter().find(|c| c.id == assertion.id) {
+        Some(credential) => credential,
+        None => {
+            return Err(Error::new(
+                ErrorKind::BadWebAuthn,
+                format!("Unknown credential {} for {}.", assertion.id, user.id),
+            ))
+        }
+    };
+    let challenge = take_challenge("login", &user.id, redis).await?;
+
+    // The latest counter is in Redis not to rewrite the user JSON on every login.
+    let key = format!("{}{}", COUNT_KEY_PREFIX, credential.id);
+    let redis = redis.lock().await;
+    let mut redis = redis.get_multiplexed_async_connection().await?;
+    let sign_count = redis
+        .get::<&String, Option<u32>>(&key)
+        .await?
+        .unwrap_or(credential.sign_count);
+
+    let sign_count =
+        RelyingParty::new(config).authenticate(&challenge, credential, sign_count, assertion)?;
+    redis.set::<&String, u32, ()>(&key, sign_count).await?;
+    Ok(())
+}
+
+fn enabled(config: &AdminConfig) -> Result<&WebAuthnConfig, Error> {
+    match &config.webauthn {
+        Some(webauthn) => Ok(webauthn),
+        None => Err(Error::new(
+            ErrorKind::NotFound,
+            "WebAuthn is not configured.",
+        )),
+    }
+}
+
+fn user_verification(config: &WebAuthnConfig) -> &'static str {
+    if config.user_verification {
+        "required"
+    } else {
+        "discouraged"
+    }
+}
+
+async fn store_challenge(
+    purpose: &str,
+    user_id: &str,
+    challenge: &str,
+    redis: &Arc<Mutex<RedisClient>>,
+) -> Result<(), Error> {
+    let key = format!("{}{}:{}", CHALLENGE_KEY_PREFIX, purpose, user_id);
+    let redis = redis.lock().await;
+    let mut redis = redis.get_multiplexed_async_connection().await?;
+    redis
+        .set_ex::<&String, &str, ()>(&key, challenge, CHALLENGE_TTL)
+        .await?;
+    Ok(())
+}
+
+// Each challenge can be answered only once.
+async fn take_challenge(
+    purpose: &str,
+    user_id: &str,
+    redis: &Arc<Mutex<RedisClient>>,
+) -> Result<String, Error> {
+    let key = format!("{}{}:{}", CHALLENGE_KEY_PREFIX, purpose, user_id);
+    let redis = redis.lock().await;
+    let mut redis = redis.get_multiplexed_async_connection().await?;
+    match redis.get_del::<&String, Option<String>>(&key).await? {
+        Some(challenge) => Ok(challenge),
+        None => Err(Error::new(
+            ErrorKind::BadWebAuthn,
+            format!("No {} challenge is pending for {}.", purpose, user_id),
+        )),
+    }
+}
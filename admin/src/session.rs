@@ -0,0 +1,115 @@
+use aes_gcm::{Aes256Gcm, Key as AesKey, KeyInit};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::{header, HeaderMap};
+use min_auth_common::{
+    error::{Error, ErrorKind},
+    utils::seal,
+};
+use serde::{Deserialize, Serialize};
+
+pub const COOKIE_NAME: &str = "min-auth-admin";
+
+// Seconds from login until a session expires
+const LIFETIME: i64 = 60 * 60;
+
+// An admin session sealed into a cookie with the session key.
+// The key is generated on start, so sessions do not survive a restart.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct AdminSession {
+    pub user_id: String,
+    // Unix time in seconds
+    pub issued_at: i64,
+    // Logged in with a WebAuthn assertion
+    pub webauthn: bool,
+}
+
+impl AdminSession {
+    pub fn new<U>(user_id: U, webauthn: bool, now: i64) -> Self
+    where
+        U: ToString,
+    {
+        Self {
+            user_id: user_id.to_string(),
+            issued_at: now,
+            webauthn,
+        }
+    }
+
+    pub fn seal(&self, key: &AesKey<Aes256Gcm>) -> Result<String, Error> {
+        let sealed = seal::seal(&Aes256Gcm::new(key), self, COOKIE_NAME.as_bytes())?;
+        Ok(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    pub fn open(value: &str, key: &AesKey<Aes256Gcm>, now: i64) -> Result<Self, Error> {
+        let sealed = URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(|e| Error::new(ErrorKind::InvalidSession, e))?;
+        let session: Self = seal::open(&Aes256Gcm::new(key), &sealed, COOKIE_NAME.as_bytes())?;
+
+        if now - session.issued_at >= LIFETIME {
+            return Err(Error::new(
+                ErrorKind::ExpiredSession,
+                format!("Admin session of {} has expired.", session.user_id),
+            ));
+        }
+        Ok(session)
+    }
+
+    // Open the session in the cookie of a request.
+    pub fn find(headers: &HeaderMap, key: &AesKey<Aes256Gcm>, now: i64) -> Result<Self, Error> {
+        for cookies in headers.get_all(header::COOKIE) {
+            let cookies = match cookies.to_str() {
+                Ok(cookies) => cookies,
+                Err(_) => continue,
+            };
+            for cookie in cookies.split(';') {
+                if let Some((COOKIE_NAME, value)) = cookie.trim().split_once('=') {
+                    return Self::open(value, key, now);
+                }
+            }
+        }
+        Err(Error::new(
+            ErrorKind::NoHeader,
+            "No admin session was found.",
+        ))
+    }
+
+    // A Set-Cookie value
+    pub fn cookie(value: &str) -> String {
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+            COOKIE_NAME, value, LIFETIME
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::OsRng;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_seal_and_open() {
+        let key = Aes256Gcm::generate_key(OsRng);
+        let session = AdminSession::new("admin-1", true, 1000);
+        let value = session.seal(&key).unwrap();
+        assert_eq!(AdminSession::open(&value, &key, 1000).unwrap(), session);
+
+        let mut headers = HeaderMap::new();
+        let cookie = format!("a=1; {}={}", COOKIE_NAME, value);
+        headers.append(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert_eq!(AdminSession::find(&headers, &key, 1000).unwrap(), session);
+        let e = AdminSession::find(&HeaderMap::new(), &key, 1000).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NoHeader);
+
+        // Expired
+        let e = AdminSession::open(&value, &key, 1000 + LIFETIME).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ExpiredSession);
+
+        // Sealed with another key, e.g. before a restart
+        let other = Aes256Gcm::generate_key(OsRng);
+        let e = AdminSession::open(&value, &other, 1000).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidSession);
+    }
+}
@@ -0,0 +1,574 @@
+use log::{error, info};
+use min_auth_common::{
+    config::{
+        admin::{AdminConfig, DriftConfig},
+        redis::{LoginAlias, RecordEncoding},
+    },
+    data::{
+        credentials::{Credentials, Keyspace},
+        tokens::{token_key, TokenCredentials},
+        users::User,
+    },
+    error::Error,
+    DynError,
+};
+use redis::{Client as RedisClient, Commands, Connection, FromRedisValue, Pipeline};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::RwLock, task::spawn_blocking};
+
+// A record in the auth Redis, where a string is JSON or the user ID of an alias
+#[derive(PartialEq, Clone, Debug)]
+pub enum Record {
+    String(String),
+    Hash(BTreeMap<String, String>),
+}
+
+impl Record {
+    // A field holding JSON in a hash (e.g. "acl") is compared as a string,
+    // and a string other than a JSON object as a single field "value".
+    fn fields(&self) -> Map<String, Value> {
+        match self {
+            Record::String(value) => match serde_json::from_str(value) {
+                Ok(fields) => fields,
+                Err(_) => Map::from_iter([("value".to_string(), Value::String(value.clone()))]),
+            },
+            Record::Hash(fields) => fields
+                .iter()
+                .map(|(field, value)| (field.clone(), Value::String(value.clone())))
+                .collect(),
+        }
+    }
+}
+
+// Keys missing from, left over in and differing in Redis compared with the
+// user directory. Mismatched keys are listed with the differing fields.
+#[derive(Serialize, Default, PartialEq, Debug)]
+pub struct SyncDiff {
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    pub mismatched: BTreeMap<String, Vec<String>>,
+}
+
+impl SyncDiff {
+    pub fn new(records: &BTreeMap<String, Record>, current: &HashMap<String, Record>) -> Self {
+        let mut diff = Self::default();
+        for (key, value) in records {
+            match current.get(key) {
+                Some(current) if current == value => {}
+                Some(current) => {
+                    diff.mismatched
+                        .insert(key.clone(), mismatched_fields(value, current));
+                }
+                None => diff.missing.push(key.clone()),
+            }
+        }
+        diff.extra = current
+            .keys()
+            .filter(|key| !records.contains_key(*key))
+            .cloned()
+            .collect();
+        diff.extra.sort();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl Display for SyncDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} missing, {} extra, {} mismatched",
+            self.missing.len(),
+            self.extra.len(),
+            self.mismatched.len()
+        )
+    }
+}
+
+// Top-level fields differing between two records (e.g. "pwhash", "acl")
+fn mismatched_fields(record: &Record, current: &Record) -> Vec<String> {
+    let (record, current) = (record.fields(), current.fields());
+    let fields: BTreeSet<&String> = record
+        .keys()
+        .chain(current.keys())
+        .filter(|field| record.get(*field) != current.get(*field))
+        .collect();
+    fields.into_iter().cloned().collect()
+}
+
+// The records the auth server reads, keyed as it looks them up.
+// An alias shared by several users is left out as it cannot be resolved.
+pub fn records<'a, I>(users: I, keys: &Keyspace) -> BTreeMap<String, Record>
+where
+    I: IntoIterator<Item = &'a User>,
+{
+    let mut records = BTreeMap::new();
+    let mut aliases: BTreeMap<String, Option<&String>> = BTreeMap::new();
+    for user in users {
+        for (alias, login) in [
+            (LoginAlias::Username, &user.username),
+            (LoginAlias::Email, &user.email),
+        ] {
+            if login.is_empty() {
+                continue;
+            }
+            aliases
+                .entry(keys.alias(alias, login))
+                .and_modify(|user_id| {
+                    if *user_id != Some(&user.id) {
+                        *user_id = None;
+                    }
+                })
+                .or_insert(Some(&user.id));
+        }
+
+        let cred = Credentials::from(user);
+        let record = match keys.encoding() {
+            RecordEncoding::Json => Record::String(String::from(&cred)),
+            RecordEncoding::Hash => Record::Hash(BTreeMap::from(&cred)),
+        };
+        records.insert(keys.user(&user.id), record);
+        for token in &user.tokens {
+            records.insert(
+                keys.token(&token.token_hash),
+                Record::String(String::from(&TokenCredentials::new(&user.id, token))),
+            );
+        }
+    }
+    for (key, user_id) in aliases {
+        match user_id {
+            Some(user_id) => {
+                records.insert(key, Record::String(user_id.clone()));
+            }
+            None => error!("{} is shared by several users and was left out.", key),
+        }
+    }
+    records
+}
+
+// Replace the records in a Redis instance in a single transaction.
+// Only the changed fields of a hash are written. Nothing is written in a dry run.
+// Keys are not watched, as min-auth-admin is the only writer of the records,
+// so a change made by hand between the reads and the transaction is overwritten.
+pub fn sync(
+    records: &BTreeMap<String, Record>,
+    keys: &Keyspace,
+    uri: &str,
+    dry_run: bool,
+) -> Result<SyncDiff, Error> {
+    let mut redis = RedisClient::open(uri)?.get_connection()?;
+    let scanned: Vec<String> = redis
+        .scan_match::<String, String>(pattern(keys.prefix()))?
+        .filter(|key| keys.contains(key))
+        .collect();
+    let current = read(&mut redis, scanned)?;
+
+    let mut diff = SyncDiff::new(records, &current);
+    if keys.prefix().is_empty() {
+        diff.extra.retain(|key| is_record(key, &current, records));
+    }
+    if dry_run || diff.is_empty() {
+        return Ok(diff);
+    }
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in &diff.extra {
+        pipe.del(key).ignore();
+    }
+    for key in &diff.missing {
+        write(&mut pipe, key, &records[key]);
+    }
+    for key in diff.mismatched.keys() {
+        match (&records[key], &current[key]) {
+            (Record::Hash(fields), Record::Hash(old)) => {
+                let changed: Vec<(&String, &String)> = fields
+                    .iter()
+                    .filter(|(field, value)| old.get(*field) != Some(*value))
+                    .collect();
+                if !changed.is_empty() {
+                    pipe.cmd("HSET").arg(key).arg(&changed).ignore();
+                }
+                let removed: Vec<&String> = old
+                    .keys()
+                    .filter(|field| !fields.contains_key(*field))
+                    .collect();
+                if !removed.is_empty() {
+                    pipe.hdel(key, removed).ignore();
+                }
+            }
+            (record, _) => {
+                pipe.del(key).ignore();
+                write(&mut pipe, key, record);
+            }
+        }
+    }
+    pipe.query::<()>(&mut redis)?;
+    Ok(diff)
+}
+
+// The keys starting with a prefix as a SCAN pattern
+fn pattern(prefix: &str) -> String {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if "*?[]\\".contains(c) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern + "*"
+}
+
+// The types and then the values of the keys, each in one round trip
+fn read(redis: &mut Connection, keys: Vec<String>) -> Result<HashMap<String, Record>, Error> {
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.key_type(key);
+    }
+    let types: Vec<String> = pipe.query(redis)?;
+
+    let mut pipe = redis::pipe();
+    let mut read = vec![];
+    for (key, key_type) in keys.into_iter().zip(types) {
+        match key_type.as_str() {
+            "string" => pipe.get(&key),
+            "hash" => pipe.hgetall(&key),
+            // Expired since scanned
+            "none" => continue,
+            // Replaced as a mismatch
+            _ => pipe.cmd("ECHO").arg(""),
+        };
+        read.push((key, key_type));
+    }
+    let values: Vec<redis::Value> = pipe.query(redis)?;
+
+    let mut current = HashMap::new();
+    for ((key, key_type), value) in read.into_iter().zip(values) {
+        let record = match key_type.as_str() {
+            "hash" => match BTreeMap::<String, String>::from_redis_value(&value)? {
+                // Expired since its type was read
+                fields if fields.is_empty() => continue,
+                fields => Record::Hash(fields),
+            },
+            _ => match Option::<String>::from_redis_value(&value)? {
+                Some(value) => Record::String(value),
+                None => continue,
+            },
+        };
+        current.insert(key, record);
+    }
+    Ok(current)
+}
+
+// Without a prefix, keys of other applications in a shared Redis may be named
+// like records, so an extra key is only taken as one if its value is a record.
+// An alias is one if it points to a user, and is left over otherwise.
+fn is_record(
+    key: &str,
+    current: &HashMap<String, Record>,
+    records: &BTreeMap<String, Record>,
+) -> bool {
+    let credentials = |record: Option<&Record>| match record {
+        Some(Record::String(value)) => Credentials::try_from(value).is_ok(),
+        Some(Record::Hash(fields)) => Credentials::try_from(fields).is_ok(),
+        None => false,
+    };
+    if key.starts_with(&token_key("")) {
+        matches!(&current[key], Record::String(value) if TokenCredentials::try_from(value).is_ok())
+    } else if LoginAlias::ALL
+        .iter()
+        .any(|alias| key.starts_with(alias.key_prefix()))
+    {
+        match &current[key] {
+            Record::String(user_id) => {
+                records.contains_key(user_id) || credentials(current.get(user_id))
+            }
+            Record::Hash(_) => false,
+        }
+    } else {
+        credentials(current.get(key))
+    }
+}
+
+// HSET rather than the deprecated HMSET of `hset_multiple`
+fn write(pipe: &mut Pipeline, key: &String, record: &Record) {
+    match record {
+        Record::String(value) => pipe.set(key, value).ignore(),
+        Record::Hash(fields) => pipe.cmd("HSET").arg(key).arg(fields).ignore(),
+    };
+}
+
+// The diff or the error of each auth Redis by name
+pub type SyncResults = BTreeMap<String, Result<SyncDiff, Error>>;
+
+// Sync every auth Redis even if one of them fails.
+// The instances are named as in the health report.
+pub fn sync_all(config: &AdminConfig, dry_run: bool) -> Result<SyncResults, Error> {
+    // Always strict, as a user left out would be deleted from Redis.
+    let users = User::load_all(&config.file_system.users, true)?;
+    let keys = Keyspace::new(&config.redis.key_prefix, config.redis.encoding);
+    let records = records(users.values(), &keys);
+    Ok(config
+        .redis
+        .auth
+        .iter()
+        .enumerate()
+        .map(|(i, uri)| {
+            (
+                format!("redis_auth_{}", i),
+                sync(&records, &keys, uri, dry_run),
+            )
+        })
+        .collect())
+}
+
+// Check for drift periodically in the admin server.
+// A failed check is logged and retried at the next interval.
+pub async fn watch(config: Arc<RwLock<AdminConfig>>, drift: DriftConfig) -> Result<(), DynError> {
+    watch_with(config, drift, sync_all).await
+}
+
+// Check with `sync`, which is `sync_all` but in tests.
+async fn watch_with<F>(
+    config: Arc<RwLock<AdminConfig>>,
+    drift: DriftConfig,
+    sync: F,
+) -> Result<(), DynError>
+where
+    F: Fn(&AdminConfig, bool) -> Result<SyncResults, Error> + Clone + Send + 'static,
+{
+    let mut interval = tokio::time::interval(Duration::from_secs(drift.interval.max(1)));
+    loop {
+        interval.tick().await;
+        let config = config.read().await.clone();
+        // The Redis connections here are blocking.
+        let sync = sync.clone();
+        let results = match spawn_blocking(move || sync(&config, !drift.repair)).await {
+            Ok(Ok(results)) => results,
+            Ok(Err(e)) => {
+                error!("{} ({})", e, e.code());
+                continue;
+            }
+            Err(e) => {
+                error!("The drift check failed: {}", e);
+                continue;
+            }
+        };
+        for (name, ret) in results {
+            match ret {
+                Ok(diff) if diff.is_empty() => info!("{}: No drift was found.", name),
+                Ok(diff) => error!(
+                    "{}: Drift was {} ({}): {}",
+                    name,
+                    if drift.repair { "repaired" } else { "found" },
+                    diff,
+                    serde_json::to_string(&diff).unwrap_or_default()
+                ),
+                Err(e) => error!("{}: {} ({})", name, e, e.code()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use min_auth_common::{
+        config::admin::{ExposeConfig, FsConfig, RedisConfig, SecurityConfig},
+        data::{tokens::ApiToken, DataLoader},
+        error::ErrorKind,
+    };
+
+    fn json(value: &str) -> Record {
+        Record::String(value.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let config = AdminConfig {
+            expose: ExposeConfig { sockets: vec![] },
+            redis: RedisConfig {
+                session: "redis://127.0.0.1:1/0".to_string(),
+                auth: vec![],
+                key_prefix: String::new(),
+                encoding: Default::default(),
+            },
+            security: SecurityConfig {
+                password_secret: "secret".to_string(),
+            },
+            file_system: FsConfig {
+                users: "../common/test/users".to_string(),
+                requests: "/nonexistent".to_string(),
+                strict: false,
+                git: None,
+            },
+            webauthn: None,
+            drift: None,
+        };
+        let drift = DriftConfig {
+            interval: 1,
+            repair: true,
+        };
+        // The first check fails and the next ones find drift.
+        let checks = Arc::new(std::sync::Mutex::new(vec![]));
+        let sync = {
+            let checks = Arc::clone(&checks);
+            move |_: &AdminConfig, dry_run: bool| {
+                let mut checks = checks.lock().unwrap();
+                checks.push(dry_run);
+                match checks.len() {
+                    1 => Err(Error::new(ErrorKind::Backend, "unreachable")),
+                    _ => Ok(SyncResults::from([(
+                        "redis_auth_0".to_string(),
+                        Ok(SyncDiff {
+                            extra: vec!["Foo3".to_string()],
+                            ..Default::default()
+                        }),
+                    )])),
+                }
+            }
+        };
+        let watching = watch_with(Arc::new(RwLock::new(config)), drift, sync);
+        let ret = tokio::time::timeout(Duration::from_millis(2500), watching).await;
+        assert!(ret.is_err(), "stopped watching");
+        // Checked at 0, 1 and 2 seconds, repairing each time
+        assert_eq!(*checks.lock().unwrap(), vec![false, false, false]);
+    }
+
+    #[test]
+    fn test_records() {
+        let mut user = User::load("../common/test/users/foo1.json").unwrap();
+        let (token, _) = ApiToken::issue("secret", "ci", vec![], None);
+        user.tokens = vec![token.clone()];
+        let keys = Keyspace::new("min-auth:cred:", RecordEncoding::Json);
+        let jsons = records([&user], &keys);
+        // The user, the token and the aliases
+        assert_eq!(jsons.len(), 4);
+
+        let cred: Credentials = match &jsons[&keys.user(&user.id)] {
+            Record::String(value) => value.try_into().unwrap(),
+            Record::Hash(_) => panic!("not JSON"),
+        };
+        assert_eq!(cred.pwhash, user.password_hash);
+        let cred: TokenCredentials = match &jsons[&keys.token(&token.token_hash)] {
+            Record::String(value) => value.try_into().unwrap(),
+            Record::Hash(_) => panic!("not JSON"),
+        };
+        assert_eq!(cred.user_id, user.id);
+
+        let keys = Keyspace::new("min-auth:cred:", RecordEncoding::Hash);
+        let hashes = records([&user], &keys);
+        let cred = match &hashes[&keys.user(&user.id)] {
+            Record::Hash(fields) => Credentials::try_from(fields).unwrap(),
+            Record::String(_) => panic!("not a hash"),
+        };
+        assert_eq!(cred.acl, user.acl);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let foo1 = User::load("../common/test/users/foo1.json").unwrap();
+        let mut foo2 = User::load("../common/test/users/foo2.json").unwrap();
+        foo2.email = foo1.email.to_uppercase();
+        let keys = Keyspace::default();
+        let records = records([&foo1, &foo2], &keys);
+
+        let alias = keys.alias(LoginAlias::Username, &foo1.username.to_uppercase());
+        assert_eq!(records[&alias], Record::String(foo1.id.clone()));
+        assert!(keys.contains(&alias));
+        // Shared by both
+        assert!(!records.contains_key(&keys.alias(LoginAlias::Email, &foo1.email)));
+    }
+
+    #[test]
+    fn test_diff() {
+        let records = BTreeMap::from([
+            ("a".to_string(), json("1")),
+            ("b".to_string(), json("2")),
+            ("c".to_string(), json("3")),
+        ]);
+        let current = HashMap::from([
+            ("b".to_string(), json("2")),
+            ("c".to_string(), json("old")),
+            ("d".to_string(), json("4")),
+        ]);
+        let diff = SyncDiff::new(&records, &current);
+        assert_eq!(diff.missing, vec!["a".to_string()]);
+        assert_eq!(diff.mismatched.keys().collect::<Vec<_>>(), vec!["c"]);
+        assert_eq!(diff.extra, vec!["d".to_string()]);
+        assert!(SyncDiff::new(&records, &records.clone().into_iter().collect()).is_empty());
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert_eq!(pattern(""), "*");
+        assert_eq!(pattern("min-auth:cred:"), "min-auth:cred:*");
+        assert_eq!(pattern("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\*");
+    }
+
+    #[test]
+    fn test_is_record() {
+        let user = User::load("../common/test/users/foo1.json").unwrap();
+        let (token, _) = ApiToken::issue("secret", "ci", vec![], None);
+        let cred = Credentials::from(&user);
+        let current = HashMap::from([
+            (user.id.clone(), json(&String::from(&cred))),
+            ("Foo2".to_string(), Record::Hash(BTreeMap::from(&cred))),
+            (
+                token_key(&token.token_hash),
+                json(&String::from(&TokenCredentials::new(&user.id, &token))),
+            ),
+            ("username:foo1".to_string(), json(&user.id)),
+            ("username:bar".to_string(), json("Bar1")),
+            ("email:bar@example.com".to_string(), json("Foo3")),
+            ("counter".to_string(), json("1")),
+            ("token:other".to_string(), json(r#"{"a":1}"#)),
+            ("cache".to_string(), json(r#"{"id":"x"}"#)),
+        ]);
+        let records = BTreeMap::from([("Foo3".to_string(), json("{}"))]);
+        let mut keys: Vec<&String> = current
+            .keys()
+            .filter(|key| is_record(key, &current, &records))
+            .collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                &"Foo1".to_string(),
+                &"Foo2".to_string(),
+                &"email:bar@example.com".to_string(),
+                &token_key(&token.token_hash),
+                &"username:foo1".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mismatched_fields() {
+        let record = json(r#"{"id":"a","pwhash":"1","acl":[]}"#);
+        let current = json(r#"{"id":"a","pwhash":"2","acl":[{"control":"allow","service":"*"}]}"#);
+        assert_eq!(mismatched_fields(&record, &current), vec!["acl", "pwhash"]);
+        assert_eq!(
+            mismatched_fields(&record, &json("broken")),
+            vec!["acl", "id", "pwhash", "value"]
+        );
+        assert_eq!(mismatched_fields(&json("foo"), &json("bar")), vec!["value"]);
+
+        let hash = |pwhash: &str| {
+            Record::Hash(BTreeMap::from([
+                ("id".to_string(), "a".to_string()),
+                ("pwhash".to_string(), pwhash.to_string()),
+            ]))
+        };
+        assert_eq!(mismatched_fields(&hash("1"), &hash("2")), vec!["pwhash"]);
+    }
+}
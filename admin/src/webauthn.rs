@@ -0,0 +1,424 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature as EdSignature, Verifier as _, VerifyingKey as EdVerifyingKey};
+use min_auth_common::{
+    config::admin::WebAuthnConfig,
+    data::webauthn::{WebAuthnCredential, COSE_EDDSA, COSE_ES256},
+    error::{Error, ErrorKind},
+};
+use p256::ecdsa::{Signature as EsSignature, VerifyingKey as EsVerifyingKey};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+
+// Flags of authenticator data
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED: u8 = 0x40;
+
+// Sent by the browser after navigator.credentials.create().
+// All the binary fields are in base64url.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct RegistrationResponse {
+    pub id: String,
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+// Sent by the browser after navigator.credentials.get().
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct AssertionResponse {
+    pub id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    // The credential ID and the COSE key on registration
+    attested: Option<(Vec<u8>, Value)>,
+}
+
+// Verifies WebAuthn responses for the admin UI without any attestation,
+// as only "none" is requested.
+#[derive(Debug)]
+pub struct RelyingParty {
+    config: WebAuthnConfig,
+}
+
+impl RelyingParty {
+    pub fn new(config: &WebAuthnConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    pub fn config(&self) -> &WebAuthnConfig {
+        &self.config
+    }
+
+    pub fn register<L>(
+        &self,
+        challenge: &str,
+        label: L,
+        res: &RegistrationResponse,
+    ) -> Result<WebAuthnCredential, Error>
+    where
+        L: Display,
+    {
+        self.check_client_data(&res.client_data_json, "webauthn.create", challenge)?;
+
+        let object: Value = serde_cbor::from_slice(&decode(&res.attestation_object)?)
+            .map_err(|e| Error::new(ErrorKind::BadWebAuthn, e))?;
+        let fmt = match map_get(&object, Value::Text("fmt".to_string())) {
+            Some(Value::Text(fmt)) => fmt,
+            _ => return Err(bad("No attestation format was found.")),
+        };
+        if fmt != "none" {
+            return Err(bad(format!("Unsupported attestation format {}.", fmt)));
+        }
+        let auth_data = match map_get(&object, Value::Text("authData".to_string())) {
+            Some(Value::Bytes(auth_data)) => AuthData::parse(auth_data)?,
+            _ => return Err(bad("No authenticator data was found.")),
+        };
+        self.check_auth_data(&auth_data)?;
+
+        let (id, key) = match auth_data.attested {
+            Some(attested) => attested,
+            None => return Err(bad("No credential was attested.")),
+        };
+        if URL_SAFE_NO_PAD.encode(&id) != res.id {
+            return Err(bad("The credential ID does not match."));
+        }
+        let (alg, public_key) = parse_cose_key(&key)?;
+
+        Ok(WebAuthnCredential {
+            id: res.id.clone(),
+            label: format!("{}", label),
+            alg,
+            public_key: URL_SAFE_NO_PAD.encode(public_key),
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    // Returns the new signature counter to be stored.
+    pub fn authenticate(
+        &self,
+        challenge: &str,
+        credential: &WebAuthnCredential,
+        sign_count: u32,
+        res: &AssertionResponse,
+    ) -> Result<u32, Error> {
+        if res.id != credential.id {
+            return Err(bad("The credential ID does not match."));
+        }
+        let client_data =
+            self.check_client_data(&res.client_data_json, "webauthn.get", challenge)?;
+        let raw_auth_data = decode(&res.authenticator_data)?;
+        let auth_data = AuthData::parse(&raw_auth_data)?;
+        self.check_auth_data(&auth_data)?;
+
+        let mut signed = raw_auth_data;
+        signed.extend(Sha256::digest(client_data));
+        verify_signature(credential, &signed, &decode(&res.signature)?)?;
+
+        // Authenticators without a counter always send 0.
+        if (auth_data.sign_count != 0 || sign_count != 0) && auth_data.sign_count <= sign_count {
+            return Err(bad(format!(
+                "The signature counter of {} went back, which implies a cloned authenticator.",
+                credential.id
+            )));
+        }
+        Ok(auth_data.sign_count)
+    }
+
+    // Returns the decoded client data to be signed.
+    fn check_client_data(
+        &self,
+        client_data_json: &str,
+        kind: &str,
+        challenge: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let raw = decode(client_data_json)?;
+        let client_data: ClientData =
+            serde_json::from_slice(&raw).map_err(|e| Error::new(ErrorKind::BadWebAuthn, e))?;
+        if client_data.kind != kind {
+            return Err(bad(format!("Unexpected type {}.", client_data.kind)));
+        }
+        if client_data.challenge != challenge {
+            return Err(bad("The challenge does not match."));
+        }
+        if client_data.origin != self.config.origin {
+            return Err(bad(format!("Unexpected origin {}.", client_data.origin)));
+        }
+        Ok(raw)
+    }
+
+    fn check_auth_data(&self, auth_data: &AuthData) -> Result<(), Error> {
+        if auth_data.rp_id_hash[..] != Sha256::digest(self.config.rp_id.as_bytes())[..] {
+            return Err(bad("The RP ID does not match."));
+        }
+        if auth_data.flags & USER_PRESENT == 0 {
+            return Err(bad("The user was not present."));
+        }
+        if self.config.user_verification && auth_data.flags & USER_VERIFIED == 0 {
+            return Err(bad("The user was not verified."));
+        }
+        Ok(())
+    }
+}
+
+impl AuthData {
+    // rpIdHash (32) | flags (1) | signCount (4) | attestedCredentialData
+    fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 37 {
+            return Err(bad("Too short authenticator data."));
+        }
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+        // aaguid (16) | credentialIdLength (2) | credentialId | credentialPublicKey
+        let attested = if flags & ATTESTED != 0 {
+            let rest = &data[37..];
+            if rest.len() < 18 {
+                return Err(bad("Too short attested credential data."));
+            }
+            let len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+            if rest.len() < 18 + len {
+                return Err(bad("Too short credential ID."));
+            }
+            let id = rest[18..18 + len].to_vec();
+            // Extensions may follow the key.
+            let key = match serde_cbor::Deserializer::from_slice(&rest[18 + len..])
+                .into_iter::<Value>()
+                .next()
+            {
+                Some(Ok(key)) => key,
+                _ => return Err(bad("No credential public key was found.")),
+            };
+            Some((id, key))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            rp_id_hash: data[..32].to_vec(),
+            flags,
+            sign_count,
+            attested,
+        })
+    }
+}
+
+// A base64url challenge to be stored until the response arrives.
+pub fn new_challenge() -> String {
+    let mut buf = [0u8; 32];
+    thread_rng().fill_bytes(&mut buf);
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+fn parse_cose_key(key: &Value) -> Result<(i64, Vec<u8>), Error> {
+    let int = |label: i128| match map_get(key, Value::Integer(label)) {
+        Some(Value::Integer(value)) => Some(*value),
+        _ => None,
+    };
+    let bytes = |label: i128| match map_get(key, Value::Integer(label)) {
+        Some(Value::Bytes(value)) => Some(value.clone()),
+        _ => None,
+    };
+
+    // kty (1), alg (3), crv (-1), x (-2) and y (-3)
+    match (int(1), int(3), int(-1), bytes(-2), bytes(-3)) {
+        (Some(2), Some(-7), Some(1), Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => {
+            let mut point = vec![0x04];
+            point.extend(x);
+            point.extend(y);
+            Ok((COSE_ES256, point))
+        }
+        (Some(1), Some(-8), Some(6), Some(x), _) if x.len() == 32 => Ok((COSE_EDDSA, x)),
+        _ => Err(bad("Only ES256 and EdDSA (Ed25519) keys are supported.")),
+    }
+}
+
+fn verify_signature(
+    credential: &WebAuthnCredential,
+    signed: &[u8],
+    signature: &[u8],
+) -> Result<(), Error> {
+    let public_key = decode(&credential.public_key)?;
+    let ret = match credential.alg {
+        COSE_ES256 => {
+            let key = EsVerifyingKey::from_sec1_bytes(&public_key)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let signature = EsSignature::from_der(signature)
+                .map_err(|e| Error::new(ErrorKind::BadWebAuthn, e))?;
+            key.verify(signed, &signature)
+        }
+        COSE_EDDSA => {
+            let public_key: [u8; 32] = match public_key.try_into() {
+                Ok(public_key) => public_key,
+                Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Bad EdDSA key.")),
+            };
+            let key = EdVerifyingKey::from_bytes(&public_key)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let signature = EdSignature::from_slice(signature)
+                .map_err(|e| Error::new(ErrorKind::BadWebAuthn, e))?;
+            key.verify(signed, &signature)
+        }
+        alg => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported algorithm {}.", alg),
+            ))
+        }
+    };
+    ret.map_err(|e| Error::new(ErrorKind::BadWebAuthn, e))
+}
+
+fn map_get(map: &Value, key: Value) -> Option<&Value> {
+    match map {
+        Value::Map(map) => map.get(&key),
+        _ => None,
+    }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Error> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|e| Error::new(ErrorKind::BadWebAuthn, e))
+}
+
+fn bad<T>(message: T) -> Error
+where
+    T: Display,
+{
+    Error::new(ErrorKind::BadWebAuthn, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value as Json;
+    use std::fs::read_to_string;
+
+    struct Fixture {
+        rp: RelyingParty,
+        registration: (String, RegistrationResponse),
+        assertion: (String, AssertionResponse),
+    }
+
+    // Recorded from a software authenticator
+    fn fixture(name: &str) -> Fixture {
+        let path = format!("test/webauthn/{}.json", name);
+        let json: Json = serde_json::from_str(&read_to_string(path).unwrap()).unwrap();
+        let rp = RelyingParty::new(&WebAuthnConfig {
+            rp_id: json["rp_id"].as_str().unwrap().to_string(),
+            rp_name: "min-auth".to_string(),
+            origin: json["origin"].as_str().unwrap().to_string(),
+            user_verification: true,
+            passwordless: true,
+        });
+        let challenge = |key: &str| json[key]["challenge"].as_str().unwrap().to_string();
+        Fixture {
+            rp,
+            registration: (
+                challenge("registration"),
+                serde_json::from_value(json["registration"].clone()).unwrap(),
+            ),
+            assertion: (
+                challenge("assertion"),
+                serde_json::from_value(json["assertion"].clone()).unwrap(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_es256() {
+        let Fixture {
+            rp,
+            registration,
+            assertion,
+        } = fixture("es256");
+
+        let cred = rp
+            .register(&registration.0, "YubiKey", &registration.1)
+            .unwrap();
+        assert_eq!(cred.alg, COSE_ES256);
+        assert_eq!(cred.label, "YubiKey".to_string());
+        assert_eq!(cred.sign_count, 1);
+
+        let count = rp
+            .authenticate(&assertion.0, &cred, cred.sign_count, &assertion.1)
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // Replayed
+        let e = rp
+            .authenticate(&assertion.0, &cred, count, &assertion.1)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadWebAuthn);
+
+        // Another challenge
+        let e = rp
+            .authenticate(&new_challenge(), &cred, cred.sign_count, &assertion.1)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadWebAuthn);
+        let e = rp
+            .register(&assertion.0, "YubiKey", &registration.1)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadWebAuthn);
+    }
+
+    #[test]
+    fn test_eddsa() {
+        let Fixture {
+            rp,
+            registration,
+            assertion,
+        } = fixture("ed25519");
+
+        let cred = rp
+            .register(&registration.0, "Passkey", &registration.1)
+            .unwrap();
+        assert_eq!(cred.alg, COSE_EDDSA);
+        assert_eq!(cred.sign_count, 0);
+
+        // No counter
+        let count = rp
+            .authenticate(&assertion.0, &cred, 0, &assertion.1)
+            .unwrap();
+        assert_eq!(count, 0);
+
+        // Tampered
+        let mut tampered = assertion.1.clone();
+        let mut signature = decode(&tampered.signature).unwrap();
+        signature[0] ^= 1;
+        tampered.signature = URL_SAFE_NO_PAD.encode(signature);
+        let e = rp
+            .authenticate(&assertion.0, &cred, 0, &tampered)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadWebAuthn);
+
+        // Another RP
+        let rp = RelyingParty::new(&WebAuthnConfig {
+            origin: "https://evil.example.com".to_string(),
+            ..rp.config().clone()
+        });
+        let e = rp
+            .authenticate(&assertion.0, &cred, 0, &assertion.1)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadWebAuthn);
+    }
+}
@@ -1,24 +1,94 @@
 use min_auth_common::{
-    config::auth::{AuthConfig, ExposeConfig, RedisConfig, SecurityConfig},
+    config::auth::{
+        AuditConfig, AuthConfig, ExposeConfig, HttpConfig, JwtConfig, LdapConfig, MtlsConfig,
+        RealmConfig, RedisConfig, SecurityConfig, SessionConfig, TotpConfig,
+    },
+    config::redis::{LoginAlias, RecordEncoding},
+    config::socket::{SocketConfig, TlsConfig},
     DynError,
 };
+use std::collections::HashMap;
 
 fn gen_config() -> Result<(), DynError> {
     let config = AuthConfig {
         expose: ExposeConfig {
             sockets: vec![
-                "127.0.0.1:50080".to_string(),
-                "127.0.0.1:50081".to_string(),
-                "127.0.0.1:50082".to_string(),
-                "127.0.0.1:50083".to_string(),
+                "127.0.0.1:50080".into(),
+                "127.0.0.1:50081".into(),
+                "127.0.0.1:50082".into(),
+                "127.0.0.1:50083".into(),
+                SocketConfig::Tls {
+                    address: "0.0.0.0:50443".to_string(),
+                    tls: TlsConfig {
+                        cert: "/etc/min-auth/tls/auth.pem".to_string(),
+                        key: "/etc/min-auth/tls/auth.key".to_string(),
+                        client_ca: None,
+                        client_auth_optional: false,
+                    },
+                },
             ],
+            metrics: Some("127.0.0.1:50090".to_string()),
+            http: HttpConfig::default(),
         },
         security: SecurityConfig {
             password_secret: "secret".to_string(),
+            login_aliases: vec![LoginAlias::Username, LoginAlias::Email],
         },
         redis: RedisConfig {
             uri: "redis://127.0.0.1/0".to_string(),
+            key_prefix: "min-auth:cred:".to_string(),
+            encoding: RecordEncoding::Json,
         },
+        realm: RealmConfig {
+            default: "min-auth".to_string(),
+            services: HashMap::from([("service 1".to_string(), "Service 1".to_string())]),
+        },
+        audit: Some(AuditConfig {
+            path: Some("/var/log/min-auth/audit.log".to_string()),
+            syslog: false,
+            success_sample_rate: 1.0,
+            trust_real_ip: true,
+        }),
+        jwt: Some(JwtConfig {
+            jwks: vec!["/etc/min-auth/jwks.json".to_string()],
+            audiences: vec!["min-auth".to_string()],
+            issuers: vec!["https://idp.example.com".to_string()],
+            user_claim: "sub".to_string(),
+            leeway: 30,
+        }),
+        session: Some(SessionConfig {
+            key: "00".repeat(32),
+            cookie_name: "min-auth-session".to_string(),
+            cookie_domain: None,
+            secure: true,
+            idle_timeout: 30 * 60,
+            absolute_timeout: 12 * 60 * 60,
+        }),
+        totp: Some(TotpConfig {
+            services: vec!["admin".to_string()],
+            header: "X-TOTP-Code".to_string(),
+            skew: 1,
+        }),
+        mtls: Some(MtlsConfig {
+            verify_header: "X-SSL-Client-Verify".to_string(),
+            subject_header: "X-SSL-Client-S-DN".to_string(),
+            fingerprint_header: "X-SSL-Client-Fingerprint".to_string(),
+            fingerprints: HashMap::from([(
+                "0123456789abcdef0123456789abcdef01234567".to_string(),
+                "ci".to_string(),
+            )]),
+            subjects: HashMap::from([("CN=deploy,O=Example".to_string(), "deploy".to_string())]),
+        }),
+        ldap: Some(LdapConfig {
+            url: "ldaps://ldap.example.com".to_string(),
+            starttls: false,
+            bind_dn: "uid={},ou=people,dc=example,dc=com".to_string(),
+            group_base: "ou=groups,dc=example,dc=com".to_string(),
+            group_filter: "(member={})".to_string(),
+            group_attribute: "cn".to_string(),
+            groups: HashMap::from([("developers".to_string(), vec!["service 1".to_string()])]),
+            timeout: 5,
+        }),
     };
     config.save("etc/min-auth/auth.toml.example")?;
 
@@ -0,0 +1,247 @@
+use chrono::{SecondsFormat, Utc};
+use hyper::HeaderMap;
+use log::error;
+use min_auth_common::{
+    config::auth::AuditConfig,
+    error::{Error, ErrorKind},
+};
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    os::unix::net::UnixDatagram,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+// LOG_AUTH (4) * 8 + LOG_INFO (6)
+const SYSLOG_PRI: u8 = 38;
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+// A record never holds password material, only who asked for what.
+#[derive(Serialize, Debug)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub user_id: Option<String>,
+    pub service: Option<String>,
+    pub client_ip: Option<String>,
+    pub decision: Decision,
+    pub reason: &'static str,
+}
+
+impl AuditRecord {
+    pub fn new(client_ip: Option<String>) -> Self {
+        Self {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            user_id: None,
+            service: None,
+            client_ip,
+            decision: Decision::Deny,
+            reason: "internal_error",
+        }
+    }
+}
+
+// Lines are written by a thread so that requests never wait for the disk or syslog.
+#[derive(Debug)]
+pub struct AuditLog {
+    sender: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+    success_sample_rate: f64,
+    trust_real_ip: bool,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> Result<Self, Error> {
+        let rate = config.success_sample_rate;
+        if !rate.is_finite() || !(0.0..=1.0).contains(&rate) {
+            return Err(Error::new(
+                ErrorKind::InvalidConfig,
+                format!("Invalid success_sample_rate: {}", rate),
+            ));
+        }
+        let file = match &config.path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        let syslog = if config.syslog {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(SYSLOG_SOCKET)?;
+            Some(socket)
+        } else {
+            None
+        };
+
+        let (sender, receiver) = channel();
+        let writer = thread::Builder::new()
+            .name("audit".to_string())
+            .spawn(move || write_lines(receiver, file, syslog))?;
+        Ok(Self {
+            sender: Some(sender),
+            writer: Some(writer),
+            success_sample_rate: rate,
+            trust_real_ip: config.trust_real_ip,
+        })
+    }
+
+    // The address forwarded by the proxy is only used if it is trusted.
+    pub fn client_ip(&self, headers: &HeaderMap, remote: Option<SocketAddr>) -> Option<String> {
+        match headers.get("x-real-ip") {
+            Some(ip) if self.trust_real_ip => ip.to_str().ok().map(|ip| ip.to_string()),
+            _ => remote.map(|addr| addr.ip().to_string()),
+        }
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        if let Some(line) = self.line(record) {
+            if let Some(sender) = &self.sender {
+                if sender.send(line).is_err() {
+                    error!("The audit writer has stopped.");
+                }
+            }
+        }
+    }
+
+    // A JSON line, or None if the record was not sampled
+    fn line(&self, record: &AuditRecord) -> Option<String> {
+        if record.decision == Decision::Allow && !thread_rng().gen_bool(self.success_sample_rate) {
+            return None;
+        }
+        match serde_json::to_string(record) {
+            Ok(line) => Some(line),
+            Err(e) => {
+                error!("Failed to serialize an audit record: {}", e);
+                None
+            }
+        }
+    }
+}
+
+// Pending lines are written before the log is closed.
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_lines(receiver: Receiver<String>, mut file: Option<File>, syslog: Option<UnixDatagram>) {
+    for line in receiver {
+        if let Some(file) = &mut file {
+            if let Err(e) = file.write_all(format!("{}\n", line).as_bytes()) {
+                error!("Failed to write an audit record: {}", e);
+            }
+        }
+
+        if let Some(syslog) = &syslog {
+            let msg = format!("<{}>min-auth: {}", SYSLOG_PRI, line);
+            if let Err(e) = syslog.send(msg.as_bytes()) {
+                error!("Failed to send an audit record to syslog: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use serde_json::Value;
+    use std::fs::read_to_string;
+    use tempfile::tempdir;
+
+    fn config(path: Option<String>, success_sample_rate: f64) -> AuditConfig {
+        AuditConfig {
+            path,
+            syslog: false,
+            success_sample_rate,
+            trust_real_ip: false,
+        }
+    }
+
+    fn record(decision: Decision) -> AuditRecord {
+        AuditRecord {
+            timestamp: "2024-01-01T00:00:00.000Z".to_string(),
+            user_id: Some("Foo1".to_string()),
+            service: Some("service 1".to_string()),
+            client_ip: Some("192.0.2.1".to_string()),
+            decision,
+            reason: "ok",
+        }
+    }
+
+    #[test]
+    fn test_lines() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&config(Some(path.to_string_lossy().to_string()), 1.0)).unwrap();
+        log.record(&record(Decision::Allow));
+        let mut denied = record(Decision::Deny);
+        denied.user_id = None;
+        denied.reason = "bad_password";
+        log.record(&denied);
+        drop(log);
+
+        let lines: Vec<Value> = read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["timestamp"], "2024-01-01T00:00:00.000Z");
+        assert_eq!(lines[0]["user_id"], "Foo1");
+        assert_eq!(lines[0]["service"], "service 1");
+        assert_eq!(lines[0]["client_ip"], "192.0.2.1");
+        assert_eq!(lines[0]["decision"], "allow");
+        assert_eq!(lines[0]["reason"], "ok");
+        assert_eq!(lines[1]["user_id"], Value::Null);
+        assert_eq!(lines[1]["decision"], "deny");
+        assert_eq!(lines[1]["reason"], "bad_password");
+    }
+
+    #[test]
+    fn test_sampling() {
+        // Failures are recorded even if no success is.
+        let log = AuditLog::open(&config(None, 0.0)).unwrap();
+        assert!(log.line(&record(Decision::Allow)).is_none());
+        assert!(log.line(&record(Decision::Deny)).is_some());
+
+        let log = AuditLog::open(&config(None, 1.0)).unwrap();
+        assert!(log.line(&record(Decision::Allow)).is_some());
+
+        for rate in [f64::NAN, f64::INFINITY, -0.1, 1.1] {
+            let e = AuditLog::open(&config(None, rate)).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidConfig);
+        }
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let remote = Some(SocketAddr::from(([127, 0, 0, 1], 10000)));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", HeaderValue::from_static("192.0.2.1"));
+
+        let log = AuditLog::open(&config(None, 1.0)).unwrap();
+        assert_eq!(log.client_ip(&headers, remote).unwrap(), "127.0.0.1");
+
+        let mut trusted = config(None, 1.0);
+        trusted.trust_real_ip = true;
+        let log = AuditLog::open(&trusted).unwrap();
+        assert_eq!(log.client_ip(&headers, remote).unwrap(), "192.0.2.1");
+        assert_eq!(
+            log.client_ip(&HeaderMap::new(), remote).unwrap(),
+            "127.0.0.1"
+        );
+    }
+}
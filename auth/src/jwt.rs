@@ -0,0 +1,249 @@
+use jsonwebtoken::{
+    decode, decode_header,
+    errors::ErrorKind as JwtErrorKind,
+    jwk::{AlgorithmParameters, EllipticCurve, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use min_auth_common::{
+    config::auth::JwtConfig,
+    error::{Error, ErrorKind},
+};
+use serde_json::{Map, Value};
+use std::{fmt::Debug, fs::read_to_string};
+
+struct Key {
+    kid: Option<String>,
+    alg: Algorithm,
+    key: DecodingKey,
+}
+
+// Verifies JWTs with keys loaded from local JWKS files.
+#[derive(Debug)]
+pub struct JwtVerifier {
+    keys: Vec<Key>,
+    validation: Validation,
+    user_claim: String,
+}
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key")
+            .field("kid", &self.kid)
+            .field("alg", &self.alg)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtVerifier {
+    pub fn load(config: &JwtConfig) -> Result<Self, Error> {
+        if config.audiences.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidConfig,
+                "No audience was specified for JWT.",
+            ));
+        }
+
+        let mut keys = Vec::new();
+        for path in &config.jwks {
+            let jwks: JwkSet = serde_json::from_str(&read_to_string(path)?)?;
+            for jwk in &jwks.keys {
+                // Only asymmetric keys of the supported algorithms are used.
+                let alg = match (&jwk.algorithm, jwk.common.key_algorithm) {
+                    (AlgorithmParameters::RSA(_), None | Some(KeyAlgorithm::RS256)) => {
+                        Algorithm::RS256
+                    }
+                    (
+                        AlgorithmParameters::EllipticCurve(params),
+                        None | Some(KeyAlgorithm::ES256),
+                    ) if params.curve == EllipticCurve::P256 => Algorithm::ES256,
+                    (
+                        AlgorithmParameters::OctetKeyPair(params),
+                        None | Some(KeyAlgorithm::EdDSA),
+                    ) if params.curve == EllipticCurve::Ed25519 => Algorithm::EdDSA,
+                    _ => continue,
+                };
+                let key = DecodingKey::from_jwk(jwk)
+                    .map_err(|e| Error::new(ErrorKind::InvalidConfig, e))?;
+                keys.push(Key {
+                    kid: jwk.common.key_id.clone(),
+                    alg,
+                    key,
+                });
+            }
+        }
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_required_spec_claims(&["exp", "aud"]);
+        validation.validate_nbf = true;
+        validation.leeway = config.leeway;
+        validation.set_audience(&config.audiences);
+        if !config.issuers.is_empty() {
+            validation.set_issuer(&config.issuers);
+        }
+
+        Ok(Self {
+            keys,
+            validation,
+            user_claim: config.user_claim.clone(),
+        })
+    }
+
+    // API tokens never contain dots, so they are not mistaken for JWTs.
+    pub fn looks_like(token: &str) -> bool {
+        token.split('.').count() == 3
+    }
+
+    // Returns the min-auth user ID in the configured claim.
+    pub fn verify(&self, token: &str) -> Result<String, Error> {
+        let header = decode_header(token).map_err(|e| Error::new(ErrorKind::InvalidToken, e))?;
+
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+
+        let candidates = self.keys.iter().filter(|key| {
+            key.alg == header.alg
+                && match &header.kid {
+                    Some(kid) => key.kid.as_ref() == Some(kid),
+                    None => true,
+                }
+        });
+        for key in candidates {
+            let claims = match decode::<Map<String, Value>>(token, &key.key, &validation) {
+                Ok(data) => data.claims,
+                Err(e) => match e.kind() {
+                    // Try the next key.
+                    JwtErrorKind::InvalidSignature => continue,
+                    JwtErrorKind::ExpiredSignature => {
+                        return Err(Error::new(ErrorKind::ExpiredToken, e))
+                    }
+                    _ => return Err(Error::new(ErrorKind::InvalidToken, e)),
+                },
+            };
+            return match claims.get(&self.user_claim).and_then(Value::as_str) {
+                Some(user_id) => Ok(user_id.to_string()),
+                None => Err(Error::new(
+                    ErrorKind::InvalidToken,
+                    format!("No {} claim was found in the JWT.", self.user_claim),
+                )),
+            };
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidToken,
+            "No key could verify the JWT.",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::fs::read;
+
+    fn verifier() -> JwtVerifier {
+        JwtVerifier::load(&JwtConfig {
+            jwks: vec!["test/jwt/jwks.json".to_string()],
+            audiences: vec!["min-auth".to_string()],
+            issuers: vec!["https://idp.example.com".to_string()],
+            user_claim: "sub".to_string(),
+            leeway: 0,
+        })
+        .unwrap()
+    }
+
+    fn sign(alg: Algorithm, kid: &str, claims: Value) -> String {
+        let key = match alg {
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&read("test/jwt/ed25519.pem").unwrap()),
+            Algorithm::ES256 => EncodingKey::from_ec_pem(&read("test/jwt/es256.pem").unwrap()),
+            _ => panic!("No test key for {:?}.", alg),
+        }
+        .unwrap();
+        let mut header = Header::new(alg);
+        header.kid = Some(kid.to_string());
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    fn claims(sub: &str, aud: &str, exp: i64, nbf: i64) -> Value {
+        json!({
+            "sub": sub,
+            "aud": aud,
+            "iss": "https://idp.example.com",
+            "exp": exp,
+            "nbf": nbf,
+        })
+    }
+
+    #[test]
+    fn test_verify() {
+        let verifier = verifier();
+        let now = Utc::now().timestamp();
+
+        let token = sign(
+            Algorithm::EdDSA,
+            "ed-1",
+            claims("user-1", "min-auth", now + 60, now),
+        );
+        assert!(JwtVerifier::looks_like(&token));
+        assert_eq!(verifier.verify(&token).unwrap(), "user-1".to_string());
+
+        let token = sign(
+            Algorithm::ES256,
+            "es-1",
+            claims("user-2", "min-auth", now + 60, now),
+        );
+        assert_eq!(verifier.verify(&token).unwrap(), "user-2".to_string());
+
+        // Wrong audience
+        let token = sign(
+            Algorithm::EdDSA,
+            "ed-1",
+            claims("user-1", "other", now + 60, now),
+        );
+        let e = verifier.verify(&token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidToken);
+
+        // Expired
+        let token = sign(
+            Algorithm::EdDSA,
+            "ed-1",
+            claims("user-1", "min-auth", now - 60, now),
+        );
+        let e = verifier.verify(&token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ExpiredToken);
+
+        // Not yet valid
+        let token = sign(
+            Algorithm::EdDSA,
+            "ed-1",
+            claims("user-1", "min-auth", now + 120, now + 60),
+        );
+        let e = verifier.verify(&token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidToken);
+
+        // Signed by a key for another kid
+        let token = sign(
+            Algorithm::EdDSA,
+            "es-1",
+            claims("user-1", "min-auth", now + 60, now),
+        );
+        let e = verifier.verify(&token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidToken);
+
+        // Symmetric algorithms are never accepted.
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("ed-1".to_string());
+        let token = encode(
+            &header,
+            &claims("user-1", "min-auth", now + 60, now),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let e = verifier.verify(&token).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidToken);
+
+        assert!(!JwtVerifier::looks_like("mat_0123456789abcdef"));
+    }
+}
@@ -0,0 +1,285 @@
+use ldap3::{
+    dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, LdapResult, Scope,
+    SearchEntry,
+};
+use min_auth_common::{
+    config::auth::LdapConfig,
+    data::{
+        credentials::{Credentials as CredData, CREDENTIALS_VERSION},
+        users::{AccessControl, AccessControlKind},
+    },
+    error::{Error, ErrorKind},
+};
+use std::{collections::HashMap, fmt::Debug, future::Future, pin::Pin, time::Duration};
+
+// The result code of a rejected bind
+const INVALID_CREDENTIALS: u32 = 49;
+
+type BindFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<Vec<String>>, Error>> + Send + 'a>>;
+
+// A directory verifying passwords by binding as the user.
+pub trait Directory: Debug + Send + Sync {
+    // Returns the groups of the user, or None if the password was rejected.
+    fn bind<'a>(&'a self, user_id: &'a str, password: &'a str) -> BindFuture<'a>;
+}
+
+#[derive(Debug)]
+pub struct LdapDirectory {
+    config: LdapConfig,
+}
+
+impl Directory for LdapDirectory {
+    fn bind<'a>(&'a self, user_id: &'a str, password: &'a str) -> BindFuture<'a> {
+        Box::pin(async move {
+            self.bind_body(user_id, password)
+                .await
+                .map_err(|e| match e {
+                    LdapError::Io { .. } | LdapError::Timeout { .. } => {
+                        Error::new(ErrorKind::Unavailable, e)
+                    }
+                    e => Error::new(ErrorKind::Backend, e),
+                })
+        })
+    }
+}
+
+impl LdapDirectory {
+    async fn bind_body(
+        &self,
+        user_id: &str,
+        password: &str,
+    ) -> Result<Option<Vec<String>>, LdapError> {
+        let timeout = Duration::from_secs(self.config.timeout);
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(timeout)
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+
+        let dn = user_dn(&self.config.bind_dn, user_id);
+        let ret = ldap
+            .with_timeout(timeout)
+            .simple_bind(&dn, password)
+            .await?;
+        if !accepted(ret)? {
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        }
+
+        // Searched as the user, so the directory must let members read their groups.
+        let filter = group_filter(&self.config.group_filter, &dn);
+        let (entries, _) = ldap
+            .with_timeout(timeout)
+            .search(
+                &self.config.group_base,
+                Scope::Subtree,
+                &filter,
+                vec![&self.config.group_attribute],
+            )
+            .await?
+            .success()?;
+        let groups = entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .filter_map(|mut entry| entry.attrs.remove(&self.config.group_attribute))
+            .flatten()
+            .collect();
+        let _ = ldap.unbind().await;
+        Ok(Some(groups))
+    }
+}
+
+// The user ID is escaped as an RDN value.
+fn user_dn(template: &str, user_id: &str) -> String {
+    template.replace("{}", &dn_escape(user_id))
+}
+
+// The DN is escaped as a filter value.
+fn group_filter(template: &str, dn: &str) -> String {
+    template.replace("{}", &ldap_escape(dn))
+}
+
+// A rejected password is not an error, unlike other failed binds.
+fn accepted(result: LdapResult) -> Result<bool, LdapError> {
+    if result.rc == INVALID_CREDENTIALS {
+        return Ok(false);
+    }
+    result.success()?;
+    Ok(true)
+}
+
+// Verifies users not registered in Redis against a directory.
+#[derive(Debug)]
+pub struct LdapBackend {
+    directory: Box<dyn Directory>,
+    // Group name -> services
+    groups: HashMap<String, Vec<String>>,
+}
+
+impl LdapBackend {
+    pub fn new(config: &LdapConfig) -> Self {
+        let directory = LdapDirectory {
+            config: config.clone(),
+        };
+        Self::with_directory(Box::new(directory), config.groups.clone())
+    }
+
+    pub fn with_directory(
+        directory: Box<dyn Directory>,
+        groups: HashMap<String, Vec<String>>,
+    ) -> Self {
+        Self { directory, groups }
+    }
+
+    // The ACL allows the services of the groups the user belongs to.
+    pub async fn verify(&self, user_id: &str, password: &str) -> Result<CredData, Error> {
+        // An empty password would be an anonymous bind and always succeed.
+        if password.is_empty() {
+            return Err(Error::new(
+                ErrorKind::BadPassword,
+                format!("Empty password for {}.", user_id),
+            ));
+        }
+        let mut groups = match self.directory.bind(user_id, password).await? {
+            Some(groups) => groups,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::BadPassword,
+                    format!("LDAP rejected the password for {}.", user_id),
+                ))
+            }
+        };
+        groups.sort();
+
+        let acl = groups
+            .iter()
+            .filter_map(|group| self.groups.get(group))
+            .flatten()
+            .map(|service| AccessControl {
+                control: AccessControlKind::Allow,
+                service: service.clone(),
+            })
+            .collect();
+        Ok(CredData {
+            version: CREDENTIALS_VERSION,
+            id: user_id.to_string(),
+            salt: String::new(),
+            pwhash: String::new(),
+            acl,
+            totp_secret: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An in-process stand-in for an LDAP server
+    #[derive(Debug)]
+    struct MemoryDirectory {
+        // User ID -> (password, groups)
+        users: HashMap<String, (String, Vec<String>)>,
+    }
+
+    impl Directory for MemoryDirectory {
+        fn bind<'a>(&'a self, user_id: &'a str, password: &'a str) -> BindFuture<'a> {
+            Box::pin(async move {
+                match self.users.get(user_id) {
+                    Some((expected, groups)) if expected == password => Ok(Some(groups.clone())),
+                    _ => Ok(None),
+                }
+            })
+        }
+    }
+
+    fn backend() -> LdapBackend {
+        let directory = MemoryDirectory {
+            users: HashMap::from([
+                (
+                    "carol".to_string(),
+                    ("pw".to_string(), vec!["developers".to_string()]),
+                ),
+                ("dave".to_string(), ("".to_string(), vec![])),
+            ]),
+        };
+        LdapBackend::with_directory(
+            Box::new(directory),
+            HashMap::from([
+                (
+                    "developers".to_string(),
+                    vec!["git".to_string(), "wiki".to_string()],
+                ),
+                ("admins".to_string(), vec!["*".to_string()]),
+            ]),
+        )
+    }
+
+    #[test]
+    fn test_templates() {
+        let template = "uid={},ou=people,dc=example,dc=com";
+        assert_eq!(
+            user_dn(template, "carol"),
+            "uid=carol,ou=people,dc=example,dc=com"
+        );
+        // No other RDN can be injected.
+        assert_eq!(
+            user_dn(template, "carol,ou=admins"),
+            "uid=carol\\2cou\\3dadmins,ou=people,dc=example,dc=com"
+        );
+        assert_eq!(
+            user_dn(template, "#x "),
+            "uid=\\23x\\20,ou=people,dc=example,dc=com"
+        );
+
+        assert_eq!(
+            group_filter("(member={})", "uid=carol,dc=example"),
+            "(member=uid=carol,dc=example)"
+        );
+        // Nor a wildcard or another filter
+        assert_eq!(
+            group_filter("(member={})", "*)(cn=admins"),
+            "(member=\\2a\\29\\28cn=admins)"
+        );
+        assert_eq!(
+            group_filter("(member={})", "uid=a\\2cb"),
+            "(member=uid=a\\5c2cb)"
+        );
+    }
+
+    #[test]
+    fn test_accepted() {
+        let result = |rc| LdapResult {
+            rc,
+            matched: String::new(),
+            text: String::new(),
+            refs: vec![],
+            ctrls: vec![],
+        };
+        assert!(accepted(result(0)).unwrap());
+        assert!(!accepted(result(INVALID_CREDENTIALS)).unwrap());
+        // e.g. unwillingToPerform
+        assert!(accepted(result(53)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let backend = backend();
+
+        let cred = backend.verify("carol", "pw").await.unwrap();
+        assert_eq!(cred.id, "carol");
+        assert!(cred.allowed("git"));
+        assert!(cred.allowed("wiki"));
+        assert!(!cred.allowed("admin"));
+        assert!(cred.totp_secret.is_none());
+
+        let e = backend.verify("carol", "wrong").await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::BadPassword);
+        let e = backend.verify("nobody", "pw").await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::BadPassword);
+
+        // Never an anonymous bind
+        let e = backend.verify("dave", "").await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::BadPassword);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{
+    find_user,
+    metrics::Metrics,
+    session::{Session, SessionCodec},
+    verify_totp,
+};
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{body::Incoming, header, Request, Response, StatusCode};
+use log::{error, info};
+use min_auth_common::{
+    config::auth::AuthConfig,
+    data::credentials::Keyspace,
+    error::{Error, ErrorKind},
+    DynError,
+};
+use redis::{AsyncCommands, Client as RedisClient};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
+
+const MAX_FORM_SIZE: usize = 8 * 1024;
+
+pub(crate) fn login_form(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, DynError> {
+    let query = parse_query(&req);
+    form(StatusCode::OK, query.get("rd"), None)
+}
+
+pub(crate) async fn login(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    config: &Arc<RwLock<AuthConfig>>,
+    sessions: &Arc<SessionCodec>,
+    metrics: &Arc<Metrics>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    let body = match Limited::new(req.into_body(), MAX_FORM_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            error!("{}", e);
+            return form(StatusCode::BAD_REQUEST, None, Some("Invalid request."));
+        }
+    };
+    let params: HashMap<String, String> = form_urlencoded::parse(&body).into_owned().collect();
+    let rd = params.get("rd");
+
+    match login_body(&params, redis, config, sessions, metrics).await {
+        Ok(cookie) => Ok(Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, safe_redirect(rd))
+            .header(header::SET_COOKIE, cookie)
+            .body("".to_string().into_bytes().into())?),
+        Err(e) => {
+            error!("{} ({})", e, e.code());
+            match e.status() {
+                StatusCode::UNAUTHORIZED => {
+                    form(e.status(), rd, Some("Invalid user ID, password or code."))
+                }
+                status => form(status, rd, Some("Please try again later.")),
+            }
+        }
+    }
+}
+
+async fn login_body(
+    params: &HashMap<String, String>,
+    redis: &Arc<Mutex<RedisClient>>,
+    config: &Arc<RwLock<AuthConfig>>,
+    sessions: &Arc<SessionCodec>,
+    metrics: &Arc<Metrics>,
+) -> Result<String, Error> {
+    let (secret, skew, keys) = {
+        let config = config.read().await;
+        let skew = config.totp.as_ref().map(|totp| totp.skew).unwrap_or(0);
+        let keys = Keyspace::new(&config.redis.key_prefix, config.redis.encoding)
+            .with_aliases(&config.security.login_aliases);
+        (config.security.password_secret.clone(), skew, keys)
+    };
+
+    let (user_id, password) = match (params.get("user_id"), params.get("password")) {
+        (Some(user_id), Some(password)) => (user_id, password),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::NoHeader,
+                "No user ID or password was posted.",
+            ))
+        }
+    };
+
+    let cred = find_user(user_id, &keys, redis, metrics).await?;
+    if !cred.verify(&secret, password) {
+        return Err(Error::new(
+            ErrorKind::BadPassword,
+            format!("Invalid password for {}.", cred.id),
+        ));
+    }
+
+    // Enrolled users give a code to use the services requiring TOTP.
+    let totp = match (&cred.totp_secret, params.get("code")) {
+        (Some(_), Some(code)) if !code.is_empty() => {
+            // A code issues one session only.
+            verify_totp(&cred, Some(code.trim()), None, skew, &keys, redis, metrics).await?;
+            true
+        }
+        _ => false,
+    };
+
+    let session = Session::new(&cred.id, totp, Utc::now().timestamp());
+    info!("Session {} was issued for {}.", session.id, session.user_id);
+    Ok(sessions.cookie(&sessions.seal(&session)?))
+}
+
+pub(crate) async fn logout(
+    req: Request<Incoming>,
+    redis: &Arc<Mutex<RedisClient>>,
+    config: &Arc<RwLock<AuthConfig>>,
+    sessions: &Arc<SessionCodec>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    let query = parse_query(&req);
+    let keys = {
+        let config = config.read().await;
+        Keyspace::new(&config.redis.key_prefix, config.redis.encoding)
+    };
+
+    // Revoke the session so that a copied cookie cannot be used anymore.
+    if let Some(value) = sessions.find(req.headers()) {
+        let now = Utc::now().timestamp();
+        if let Ok(session) = sessions.open(&value, now) {
+            let revoke = async {
+                let redis = redis.lock().await;
+                let mut redis = redis.get_multiplexed_async_connection().await?;
+                let ttl = sessions.remaining(&session, now).max(1) as u64;
+                redis
+                    .set_ex::<String, u8, ()>(keys.revoked_session(&session.id), 1, ttl)
+                    .await
+            };
+            match revoke.await {
+                Ok(_) => info!("Session {} of {} was revoked.", session.id, session.user_id),
+                Err(e) => {
+                    error!("{}", e);
+                    return Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body("".to_string().into_bytes().into())?);
+                }
+            }
+        }
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, safe_redirect(query.get("rd")))
+        .header(header::SET_COOKIE, sessions.clear_cookie())
+        .body("".to_string().into_bytes().into())?)
+}
+
+fn form(
+    status: StatusCode,
+    rd: Option<&String>,
+    message: Option<&str>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    let message = match message {
+        Some(message) => format!("<p class=\"error\">{}</p>\n", escape_html(message)),
+        None => "".to_string(),
+    };
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Login</title></head>
+<body>
+{}<form method="post" action="login">
+<input type="hidden" name="rd" value="{}">
+<p><label>User ID <input name="user_id" autocomplete="username" required></label></p>
+<p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+<p><label>Code <input name="code" inputmode="numeric" autocomplete="one-time-code"></label></p>
+<p><button type="submit">Login</button></p>
+</form>
+</body>
+</html>
+"#,
+        message,
+        escape_html(&safe_redirect(rd))
+    );
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(body.into_bytes().into())?)
+}
+
+fn parse_query(req: &Request<Incoming>) -> HashMap<String, String> {
+    match req.uri().query() {
+        Some(query) => form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect(),
+        None => HashMap::new(),
+    }
+}
+
+// Only paths on the same origin are allowed not to be an open redirector.
+// Browsers drop tabs and newlines in URLs, so "/\t/host" would become "//host".
+fn safe_redirect(rd: Option<&String>) -> String {
+    match rd {
+        Some(rd)
+            if rd.starts_with('/')
+                && !rd.starts_with("//")
+                && !rd.starts_with("/\\")
+                && !rd.chars().any(|c| c.is_control()) =>
+        {
+            rd.clone()
+        }
+        _ => "/".to_string(),
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_redirect() {
+        let redirect = |rd: &str| safe_redirect(Some(&rd.to_string()));
+        assert_eq!(redirect("/app?x=1"), "/app?x=1");
+        for rd in ["//evil", "/\\evil", "https://evil", "evil", "/\t/evil", ""] {
+            assert_eq!(redirect(rd), "/", "{:?}", rd);
+        }
+        assert_eq!(safe_redirect(None), "/");
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x" title='y'>&</a>"#),
+            "&lt;a href=&quot;x&quot; title=&#39;y&#39;&gt;&amp;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("/app"), "/app");
+    }
+}
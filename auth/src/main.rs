@@ -1,19 +1,44 @@
+use audit::{AuditLog, AuditRecord, Decision};
 use bytes::Bytes;
+use chrono::Utc;
 use getopts::Options;
 use http_auth_basic::Credentials;
 use http_body_util::Full;
 use hyper::{
-    body::Incoming, header, server::conn::http1, service::Service as HyperService, Method, Request,
-    Response, StatusCode,
+    body::Incoming, header, service::Service as HyperService, Method, Request, Response, StatusCode,
 };
-use hyper_util::rt::TokioIo;
+use jwt::JwtVerifier;
+use ldap::LdapBackend;
 use log::error;
+use metrics::{Metrics, MetricsService};
 use min_auth_common::{
-    config::auth::AuthConfig, data::credentials::Credentials as CredData, error::Error, DynError,
+    config::{
+        auth::{AuthConfig, TotpConfig},
+        redis::RecordEncoding,
+    },
+    data::{
+        credentials::{Credentials as CredData, Keyspace},
+        tokens::{hash_token, TokenCredentials},
+    },
+    error::{Error, ErrorKind},
+    health::{check_redis, health, ComponentHealth, HealthReport},
+    tls::TlsTerminator,
+    utils::totp,
+    DynError,
 };
-use redis::{AsyncCommands, Client as RedisClient};
+use mtls::CertificateMapper;
+use redis::{AsyncCommands, Client as RedisClient, ExistenceCheck, SetExpiry, SetOptions};
+use server::{Limits, RequestCounter};
+use session::SessionCodec;
 use std::{
-    collections::HashMap, env, future::Future, net::SocketAddr, pin::Pin, str::FromStr, sync::Arc,
+    collections::{BTreeMap, HashMap},
+    env,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
 };
 use tokio::{
     net::TcpListener,
@@ -21,6 +46,18 @@ use tokio::{
     task::JoinSet,
 };
 
+mod audit;
+mod jwt;
+mod ldap;
+mod login;
+mod metrics;
+mod mtls;
+mod server;
+mod session;
+
+// Marks a code used by a login, which no Basic credential matches
+const TOTP_USED: &str = "1";
+
 #[tokio::main]
 async fn main() -> Result<(), DynError> {
     env_logger::init();
@@ -40,14 +77,40 @@ async fn main() -> Result<(), DynError> {
     // If the "u" flag is speficied, just show all sockets.
     if matches.opt_present("u") {
         for socket in config.expose.sockets {
-            println!("{}", socket);
+            println!("{}", socket.address());
         }
         return Ok(());
     }
 
     let sockets = config.expose.sockets.clone();
+    let metrics_socket = config.expose.metrics.clone();
+    let http = config.expose.http.clone();
+    let max_requests = http.max_requests;
     let redis_uri = config.redis.uri.clone();
+    let audit = match &config.audit {
+        Some(audit) => Some(Arc::new(AuditLog::open(audit)?)),
+        None => None,
+    };
+    let verifiers = Verifiers {
+        jwt: match &config.jwt {
+            Some(jwt) => Some(Arc::new(JwtVerifier::load(jwt)?)),
+            None => None,
+        },
+        sessions: match &config.session {
+            Some(session) => Some(Arc::new(SessionCodec::new(session)?)),
+            None => None,
+        },
+        mtls: config
+            .mtls
+            .as_ref()
+            .map(|mtls| Arc::new(CertificateMapper::new(mtls))),
+        ldap: config
+            .ldap
+            .as_ref()
+            .map(|ldap| Arc::new(LdapBackend::new(ldap))),
+    };
 
+    let metrics = Arc::new(Metrics::new(&config));
     let config = Arc::new(RwLock::new(config));
 
     let mut join_set: JoinSet<Result<(), DynError>> = JoinSet::new();
@@ -57,19 +120,97 @@ async fn main() -> Result<(), DynError> {
         let config = Arc::clone(&config);
         let redis = RedisClient::open(redis_uri.as_str())?;
         let redis = Arc::new(Mutex::new(redis));
+        let audit = audit.clone();
+        let verifiers = verifiers.clone();
+        let metrics = Arc::clone(&metrics);
+        let builder = Arc::new(server::builder(&http));
+        let limits = Limits::new(&http);
+        let tls = match socket.tls() {
+            Some(tls) => Some(Arc::new(TlsTerminator::load(tls)?)),
+            None => None,
+        };
+
+        join_set.spawn(async move {
+            let addr = SocketAddr::from_str(socket.address())?;
+            let listener = TcpListener::bind(addr).await?;
+            let svc = Service {
+                config,
+                redis,
+                audit,
+                verifiers,
+                metrics,
+                remote: None,
+                requests: Arc::default(),
+                limits,
+            };
+            loop {
+                let (stream, remote) = listener.accept().await?;
+                let permit = match svc.limits.connection() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        // Closed at once rather than logged, as when overloaded
+                        svc.metrics.reject_connection();
+                        drop(stream);
+                        continue;
+                    }
+                };
+                let svc_clone = Service {
+                    remote: Some(remote),
+                    requests: Arc::new(RequestCounter::new(max_requests)),
+                    ..svc.clone()
+                };
+                let tls = tls.clone();
+                let builder = Arc::clone(&builder);
+                let conn = svc.metrics.connect();
+                tokio::task::spawn(async move {
+                    let ret = match tls {
+                        Some(tls) => match svc_clone.limits.idle(tls.accept(stream)).await {
+                            Ok(stream) => server::serve(&builder, stream, svc_clone).await,
+                            Err(e) => {
+                                error!("TLS handshake with {} failed: {}", remote, e);
+                                return;
+                            }
+                        },
+                        None => server::serve(&builder, stream, svc_clone).await,
+                    };
+                    if let Err(err) = ret {
+                        error!("{:?}", err);
+                    }
+                    drop(conn);
+                    drop(permit);
+                });
+            }
+        });
+    }
+
+    // Metrics Service
+    if let Some(socket) = metrics_socket {
+        let svc = MetricsService { metrics };
+        let builder = Arc::new(server::builder(&http));
+        let limits = Limits::new(&http);
 
         join_set.spawn(async move {
             let addr = SocketAddr::from_str(socket.as_str())?;
             let listener = TcpListener::bind(addr).await?;
-            let svc = Service { config, redis };
             loop {
                 let (stream, _) = listener.accept().await?;
-                let io = TokioIo::new(stream);
+                let permit = match limits.connection() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        drop(stream);
+                        continue;
+                    }
+                };
                 let svc_clone = svc.clone();
+                let builder = Arc::clone(&builder);
+                let limits = limits.clone();
                 tokio::task::spawn(async move {
-                    if let Err(err) = http1::Builder::new().serve_connection(io, svc_clone).await {
+                    if let Err(err) =
+                        server::serve_metrics(&builder, &limits, stream, svc_clone).await
+                    {
                         error!("{:?}", err);
                     }
+                    drop(permit);
                 });
             }
         });
@@ -91,10 +232,25 @@ async fn main() -> Result<(), DynError> {
     Ok(())
 }
 
+// Credentials accepted in addition to the user records
+#[derive(Debug, Clone)]
+struct Verifiers {
+    jwt: Option<Arc<JwtVerifier>>,
+    sessions: Option<Arc<SessionCodec>>,
+    mtls: Option<Arc<CertificateMapper>>,
+    ldap: Option<Arc<LdapBackend>>,
+}
+
 #[derive(Debug, Clone)]
 struct Service {
     config: Arc<RwLock<AuthConfig>>,
     redis: Arc<Mutex<RedisClient>>,
+    audit: Option<Arc<AuditLog>>,
+    verifiers: Verifiers,
+    metrics: Arc<Metrics>,
+    remote: Option<SocketAddr>,
+    requests: Arc<RequestCounter>,
+    limits: Limits,
 }
 
 impl HyperService<Request<Incoming>> for Service {
@@ -105,86 +261,694 @@ impl HyperService<Request<Incoming>> for Service {
     fn call(&self, req: Request<Incoming>) -> Self::Future {
         let config = Arc::clone(&self.config);
         let redis = Arc::clone(&self.redis);
+        let audit = self.audit.clone();
+        let verifiers = self.verifiers.clone();
+        let metrics = Arc::clone(&self.metrics);
+        let remote = self.remote;
+        self.requests.count();
+        let permit = match self.limits.request() {
+            Ok(permit) => permit,
+            Err(e) => {
+                self.metrics.shed_request();
+                return Box::pin(async move { overloaded(e) });
+            }
+        };
+        let request = self.metrics.request();
 
         Box::pin(async move {
+            let _permit = permit;
+            let _request = request;
             let method = req.method();
             let path = req.uri().path();
             match (method, path) {
-                (&Method::GET, "/auth") => auth(req, &redis, &config).await,
-                (method, path) => {
-                    Err(Error::new(format!("Illegal request ({} {})", method, path)).into())
+                (&Method::GET, "/auth") => {
+                    auth(req, &redis, &config, &audit, &verifiers, &metrics, remote).await
                 }
+                (&Method::GET, "/login") if verifiers.sessions.is_some() => login::login_form(req),
+                (&Method::POST, "/login") => match &verifiers.sessions {
+                    Some(sessions) => login::login(req, &redis, &config, sessions, &metrics).await,
+                    None => not_found(req),
+                },
+                (&Method::GET | &Method::POST, "/logout") => match &verifiers.sessions {
+                    Some(sessions) => login::logout(req, &redis, &config, sessions).await,
+                    None => not_found(req),
+                },
+                (&Method::GET, "/healthz") => Ok(health(&HealthReport::new())?),
+                (&Method::GET, "/readyz") => readyz(&redis, &config).await,
+                _ => not_found(req),
             }
         })
     }
 }
 
+fn not_found(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, DynError> {
+    let e = Error::new(
+        ErrorKind::NotFound,
+        format!("Illegal request ({} {})", req.method(), req.uri().path()),
+    );
+    error!("{}", e);
+    Ok(Response::builder()
+        .status(e.status())
+        .body("".to_string().into_bytes().into())?)
+}
+
+// Not logged to keep an overloaded server from writing more.
+fn overloaded(e: Error) -> Result<Response<Full<Bytes>>, DynError> {
+    Ok(Response::builder()
+        .status(e.status())
+        .header(header::RETRY_AFTER, "1")
+        .body("".to_string().into_bytes().into())?)
+}
+
 async fn auth(
     req: Request<Incoming>,
     redis: &Arc<Mutex<RedisClient>>,
     config: &Arc<RwLock<AuthConfig>>,
+    audit: &Option<Arc<AuditLog>>,
+    verifiers: &Verifiers,
+    metrics: &Arc<Metrics>,
+    remote: Option<SocketAddr>,
 ) -> Result<Response<Full<Bytes>>, DynError> {
-    match auth_body(req, redis, config).await {
-        Ok(res) => Ok(res),
+    let client_ip = match audit {
+        Some(audit) => audit.client_ip(req.headers(), remote),
+        None => remote.map(|addr| addr.ip().to_string()),
+    };
+    let mut record = AuditRecord::new(client_ip);
+
+    let ret = match auth_body(req, redis, config, verifiers, metrics, &mut record).await {
+        Ok(res) => {
+            record.decision = Decision::Allow;
+            record.reason = "ok";
+            Ok(res)
+        }
         Err(e) => {
-            error!("{}", e);
-            Ok(Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .body("".to_string().into_bytes().into())?)
+            error!("{} ({})", e, e.code());
+            record.reason = e.code();
+            let mut res = Response::builder().status(e.status());
+            if e.status() == StatusCode::UNAUTHORIZED {
+                let config = config.read().await;
+                let realm = config.realm.get(record.service.as_deref());
+                res = res.header(
+                    header::WWW_AUTHENTICATE,
+                    format!("Basic realm=\"{}\"", escape_quoted(realm)),
+                );
+            }
+            Ok(res.body("".to_string().into_bytes().into())?)
         }
+    };
+
+    metrics.observe_decision(&record);
+    if let Some(audit) = audit {
+        audit.record(&record);
     }
+
+    ret
 }
 
 async fn auth_body(
     req: Request<Incoming>,
     redis: &Arc<Mutex<RedisClient>>,
     config: &Arc<RwLock<AuthConfig>>,
-) -> Result<Response<Full<Bytes>>, DynError> {
-    let secret = {
+    verifiers: &Verifiers,
+    metrics: &Arc<Metrics>,
+    record: &mut AuditRecord,
+) -> Result<Response<Full<Bytes>>, Error> {
+    let (secret, totp, keys) = {
         let config = config.read().await;
-        config.security.password_secret.clone()
-    };
-
-    // Retrieve credentials
-    let basic = match req.headers().get(header::AUTHORIZATION) {
-        Some(basic) => basic.to_str()?.to_string(),
-        None => return Err(Error::new("No authorization header was found.").into()),
-    };
-    let basic = match Credentials::from_header(basic) {
-        Ok(basic) => basic,
-        Err(e) => return Err(Error::new(e).into()),
+        (
+            config.security.password_secret.clone(),
+            config.totp.clone(),
+            Keyspace::new(&config.redis.key_prefix, config.redis.encoding)
+                .with_aliases(&config.security.login_aliases),
+        )
     };
 
-    // Retrieve service name
+    // Retrieve service name first to decide the realm on failure
     let query = match req.uri().query() {
         Some(query) => query,
-        None => return Err(Error::new("No query was specified.").into()),
+        None => return Err(Error::new(ErrorKind::BadRequest, "No query was specified.")),
     };
     let query: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
         .into_owned()
         .collect();
     let service = match query.get("service") {
         Some(service) => service,
-        None => return Err(Error::new("No service was speficied.").into()),
+        None => {
+            return Err(Error::new(
+                ErrorKind::BadRequest,
+                "No service was speficied.",
+            ))
+        }
     };
+    record.service = Some(service.clone());
 
-    // Retrieve a credential JSON from the Redis server
-    let cred = {
-        let redis = redis.lock().await;
-        let mut redis = redis.get_multiplexed_async_connection().await?;
-        redis.get::<&String, String>(&basic.user_id).await?
+    // Only the services in the policy require TOTP.
+    let totp = totp.filter(|totp| totp.required(service));
+    let code = match &totp {
+        Some(totp) => match req.headers().get(&totp.header) {
+            Some(code) => match code.to_str() {
+                Ok(code) => Some(code.trim().to_string()),
+                Err(e) => return Err(Error::new(ErrorKind::MalformedHeader, e)),
+            },
+            None => None,
+        },
+        None => None,
+    };
+
+    // Retrieve credentials
+    let authorization = match req.headers().get(header::AUTHORIZATION) {
+        Some(authorization) => match authorization.to_str() {
+            Ok(authorization) => Some(authorization.to_string()),
+            Err(e) => return Err(Error::new(ErrorKind::MalformedHeader, e)),
+        },
+        None => None,
+    };
+    let certificate = match (&authorization, &verifiers.mtls) {
+        (None, Some(mtls)) => mtls.user_id(req.headers())?,
+        _ => None,
+    };
+    let cookie = match &verifiers.sessions {
+        Some(sessions) => sessions.find(req.headers()).map(|value| (sessions, value)),
+        None => None,
     };
-    let cred: CredData = (&cred).try_into()?;
 
     // Verify
-    if !cred.verify(&secret, &basic.password) {
-        return Err(Error::new(format!("Invalid password for {}.", cred.id)).into());
+    let mut set_cookie = None;
+    let cred = match (authorization, certificate, cookie) {
+        (Some(authorization), _, _) => match authorization.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+                let token = token.trim();
+                match &verifiers.jwt {
+                    Some(jwt) if JwtVerifier::looks_like(token) => {
+                        verify_jwt(token, jwt, &keys, redis, metrics, record).await?
+                    }
+                    _ => {
+                        verify_token(token, service, &secret, &keys, redis, metrics, record).await?
+                    }
+                }
+            }
+            _ => {
+                let totp = totp.as_ref().map(|totp| (totp, code));
+                verify_basic(
+                    authorization,
+                    &secret,
+                    totp,
+                    &verifiers.ldap,
+                    &keys,
+                    redis,
+                    metrics,
+                    record,
+                )
+                .await?
+            }
+        },
+        (None, Some(user_id), _) => {
+            record.user_id = Some(user_id.clone());
+            get_user(&user_id, &keys, redis, metrics).await?
+        }
+        (None, None, Some((sessions, value))) => {
+            let (cred, cookie) = verify_session(
+                &value,
+                sessions,
+                totp.is_some(),
+                &keys,
+                redis,
+                metrics,
+                record,
+            )
+            .await?;
+            set_cookie = cookie;
+            cred
+        }
+        (None, None, None) => {
+            return Err(Error::new(
+                ErrorKind::NoHeader,
+                "No authorization header was found.",
+            ))
+        }
+    };
+    if !cred.allowed(service) {
+        return Err(Error::new(
+            ErrorKind::ServiceDenied,
+            format!("{} is not allowed for {}.", service, cred.id),
+        ));
     }
-    if !cred.allowed(&service) {
-        return Err(Error::new(format!("{} is not allowed for {}.", service, cred.id)).into());
+
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(cookie) = set_cookie {
+        // NGINX has to pass it with auth_request_set and add_header.
+        res = res.header(header::SET_COOKIE, cookie);
     }
+    Ok(res.body("".to_string().into_bytes().into())?)
+}
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .body("".to_string().into_bytes().into())?)
+#[allow(clippy::too_many_arguments)]
+async fn verify_basic(
+    authorization: String,
+    secret: &str,
+    totp: Option<(&TotpConfig, Option<String>)>,
+    ldap: &Option<Arc<LdapBackend>>,
+    keys: &Keyspace,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+    record: &mut AuditRecord,
+) -> Result<CredData, Error> {
+    let basic = match Credentials::from_header(authorization) {
+        Ok(basic) => basic,
+        Err(e) => return Err(Error::new(ErrorKind::MalformedHeader, e)),
+    };
+    record.user_id = Some(basic.user_id.clone());
+
+    // Local users take precedence over the directory.
+    // Directory users have no TOTP secret, so their password is passed as is.
+    let cred = match (find_user(&basic.user_id, keys, redis, metrics).await, ldap) {
+        (Err(e), Some(ldap)) if e.kind() == ErrorKind::UnknownUser => {
+            directory_totp(&basic.user_id, &totp)?;
+            return ldap.verify(&basic.user_id, &basic.password).await;
+        }
+        (cred, _) => cred?,
+    };
+    // Logged in with an alias
+    record.user_id = Some(cred.id.clone());
+
+    // Without the header, the code is at the end of the password.
+    let (password, code) = match &totp {
+        Some((_, Some(code))) => (basic.password.as_str(), Some(code.as_str())),
+        Some((_, None)) => match totp::split_code(&basic.password) {
+            Some((password, code)) => (password, Some(code)),
+            None => (basic.password.as_str(), None),
+        },
+        None => (basic.password.as_str(), None),
+    };
+    if !cred.verify(secret, password) {
+        return Err(Error::new(
+            ErrorKind::BadPassword,
+            format!("Invalid password for {}.", cred.id),
+        ));
+    }
+    if let Some((totp, _)) = totp {
+        // NGINX sends the same header with every request, which may use the code again.
+        // It is keyed like a token so that Redis never holds the password.
+        let credential = hash_token(
+            secret,
+            format!(
+                "{}:{}:{}",
+                basic.user_id,
+                basic.password,
+                code.unwrap_or("")
+            ),
+        );
+        verify_totp(
+            &cred,
+            code,
+            Some(&credential),
+            totp.skew,
+            keys,
+            redis,
+            metrics,
+        )
+        .await?;
+    }
+
+    Ok(cred)
+}
+
+// A service requiring TOTP refuses directory users, who have no secret to check.
+fn directory_totp<T>(user_id: &str, totp: &Option<T>) -> Result<(), Error> {
+    match totp {
+        Some(_) => Err(Error::new(
+            ErrorKind::TotpRequired,
+            format!("No TOTP secret is enrolled for directory user {}.", user_id),
+        )),
+        None => Ok(()),
+    }
+}
+
+// Each code is accepted only once within its validity, except with the same
+// `credential` (a hash of the whole Basic credential) that used it first.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn verify_totp(
+    cred: &CredData,
+    code: Option<&str>,
+    credential: Option<&str>,
+    skew: u64,
+    keys: &Keyspace,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+) -> Result<(), Error> {
+    let secret = match &cred.totp_secret {
+        Some(secret) => secret,
+        None => {
+            return Err(Error::new(
+                ErrorKind::TotpRequired,
+                format!("No TOTP secret is enrolled for {}.", cred.id),
+            ))
+        }
+    };
+    let code = match code {
+        Some(code) => code,
+        None => {
+            return Err(Error::new(
+                ErrorKind::TotpRequired,
+                format!("No TOTP code was given for {}.", cred.id),
+            ))
+        }
+    };
+    let step = match totp::verify(secret, code, Utc::now().timestamp(), skew) {
+        Some(step) => step,
+        None => {
+            return Err(Error::new(
+                ErrorKind::BadTotp,
+                format!("Invalid TOTP code for {}.", cred.id),
+            ))
+        }
+    };
+
+    let key = keys.totp_used(&cred.id, step);
+    let ttl = (2 * skew + 1) * totp::STEP as u64;
+    let used = credential.unwrap_or(TOTP_USED);
+    if set_record_nx(&key, used, ttl, redis, metrics).await? {
+        return Ok(());
+    }
+    let first = get_record(&key, redis, metrics).await?;
+    reused_totp(&cred.id, credential, first.as_deref())
+}
+
+// Whether a used code may be used again by a credential
+fn reused_totp(user_id: &str, credential: Option<&str>, first: Option<&str>) -> Result<(), Error> {
+    match (credential, first) {
+        (Some(credential), Some(first)) if credential == first => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::BadTotp,
+            format!("TOTP code for {} was already used.", user_id),
+        )),
+    }
+}
+
+async fn verify_token(
+    token: &str,
+    service: &str,
+    secret: &str,
+    keys: &Keyspace,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+    record: &mut AuditRecord,
+) -> Result<CredData, Error> {
+    let key = keys.token(hash_token(secret, token));
+    let token = match get_record(&key, redis, metrics).await? {
+        Some(token) => token,
+        None => return Err(Error::new(ErrorKind::InvalidToken, "Unknown token.")),
+    };
+    let token: TokenCredentials = (&token).try_into()?;
+    record.user_id = Some(token.user_id.clone());
+
+    if token.expired(Utc::now().timestamp()) {
+        return Err(Error::new(
+            ErrorKind::ExpiredToken,
+            format!("Token {} of {} has expired.", token.id, token.user_id),
+        ));
+    }
+    if !token.allowed(service) {
+        return Err(Error::new(
+            ErrorKind::ServiceDenied,
+            format!(
+                "{} is not allowed for token {} of {}.",
+                service, token.id, token.user_id
+            ),
+        ));
+    }
+
+    // The user must still exist and be allowed as well.
+    get_user(&token.user_id, keys, redis, metrics).await
+}
+
+async fn verify_jwt(
+    token: &str,
+    jwt: &JwtVerifier,
+    keys: &Keyspace,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+    record: &mut AuditRecord,
+) -> Result<CredData, Error> {
+    let user_id = jwt.verify(token)?;
+    record.user_id = Some(user_id.clone());
+
+    // The signature is enough, but the ACL is still in Redis.
+    get_user(&user_id, keys, redis, metrics).await
+}
+
+// Returns a refreshed cookie as well when the session has been touched.
+async fn verify_session(
+    value: &str,
+    sessions: &SessionCodec,
+    require_totp: bool,
+    keys: &Keyspace,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+    record: &mut AuditRecord,
+) -> Result<(CredData, Option<String>), Error> {
+    let now = Utc::now().timestamp();
+    let session = sessions.open(value, now)?;
+    record.user_id = Some(session.user_id.clone());
+
+    if require_totp && !session.totp {
+        return Err(Error::new(
+            ErrorKind::TotpRequired,
+            format!(
+                "Session {} of {} was issued without TOTP.",
+                session.id, session.user_id
+            ),
+        ));
+    }
+
+    if get_record(&keys.revoked_session(&session.id), redis, metrics)
+        .await?
+        .is_some()
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidSession,
+            format!("Session {} of {} was revoked.", session.id, session.user_id),
+        ));
+    }
+
+    let cred = get_user(&session.user_id, keys, redis, metrics).await?;
+    let cookie = match sessions.touch(&session, now) {
+        Some(session) => Some(sessions.cookie(&sessions.seal(&session)?)),
+        None => None,
+    };
+    Ok((cred, cookie))
+}
+
+async fn get_user(
+    user_id: &String,
+    keys: &Keyspace,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+) -> Result<CredData, Error> {
+    unknown_key(user_id)?;
+    let key = keys.user(user_id);
+    let cred = match keys.encoding() {
+        RecordEncoding::Json => match get_record(&key, redis, metrics).await? {
+            Some(cred) => Some((&cred).try_into()?),
+            None => None,
+        },
+        RecordEncoding::Hash => {
+            let fields = get_fields(&key, redis, metrics).await?;
+            match fields.is_empty() {
+                true => None,
+                false => Some((&fields).try_into()?),
+            }
+        }
+    };
+    match cred {
+        Some(cred) => Ok(cred),
+        None => Err(Error::new(
+            ErrorKind::UnknownUser,
+            format!("{} was not found.", user_id),
+        )),
+    }
+}
+
+// IDs and logins never contain ':', which would look up another kind of key
+// (e.g. "token:<hash>") and fail on its type rather than as an unknown user.
+fn unknown_key(login: &str) -> Result<(), Error> {
+    match login.contains(':') {
+        true => Err(Error::new(
+            ErrorKind::UnknownUser,
+            format!("{} was not found.", login),
+        )),
+        false => Ok(()),
+    }
+}
+
+// Find a user by the ID or one of the allowed aliases given at login.
+async fn find_user(
+    login: &String,
+    keys: &Keyspace,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+) -> Result<CredData, Error> {
+    unknown_key(login)?;
+    let ret = get_user(login, keys, redis, metrics).await;
+    match &ret {
+        Err(e) if e.kind() == ErrorKind::UnknownUser => {}
+        _ => return ret,
+    }
+    for alias in keys.aliases() {
+        if let Some(user_id) = get_record(&keys.alias(*alias, login), redis, metrics).await? {
+            return get_user(&user_id, keys, redis, metrics).await;
+        }
+    }
+    ret
+}
+
+// Retrieve a JSON from the Redis server
+async fn get_record(
+    key: &String,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+) -> Result<Option<String>, Error> {
+    let started = Instant::now();
+    let ret = async {
+        let redis = redis.lock().await;
+        let mut redis = redis.get_multiplexed_async_connection().await?;
+        redis.get::<&String, Option<String>>(key).await
+    }
+    .await;
+    metrics.observe_redis_latency(started.elapsed());
+    Ok(ret?)
+}
+
+// Retrieve all the fields of a hash, which are empty if the key does not exist
+async fn get_fields(
+    key: &String,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+) -> Result<BTreeMap<String, String>, Error> {
+    let started = Instant::now();
+    let ret = async {
+        let redis = redis.lock().await;
+        let mut redis = redis.get_multiplexed_async_connection().await?;
+        redis
+            .hgetall::<&String, BTreeMap<String, String>>(key)
+            .await
+    }
+    .await;
+    metrics.observe_redis_latency(started.elapsed());
+    Ok(ret?)
+}
+
+// Returns false if the key already exists.
+async fn set_record_nx(
+    key: &String,
+    value: &str,
+    ttl: u64,
+    redis: &Arc<Mutex<RedisClient>>,
+    metrics: &Arc<Metrics>,
+) -> Result<bool, Error> {
+    let options = SetOptions::default()
+        .conditional_set(ExistenceCheck::NX)
+        .with_expiration(SetExpiry::EX(ttl));
+    let started = Instant::now();
+    let ret = async {
+        let redis = redis.lock().await;
+        let mut redis = redis.get_multiplexed_async_connection().await?;
+        redis
+            .set_options::<&String, &str, Option<String>>(key, value, options)
+            .await
+    }
+    .await;
+    metrics.observe_redis_latency(started.elapsed());
+    Ok(ret?.is_some())
+}
+
+async fn readyz(
+    redis: &Arc<Mutex<RedisClient>>,
+    config: &Arc<RwLock<AuthConfig>>,
+) -> Result<Response<Full<Bytes>>, DynError> {
+    let mut report = HealthReport::new();
+
+    // The config has been loaded once the service is running.
+    report.add("config", ComponentHealth::ok());
+
+    report.add(
+        "password_secret",
+        if config.read().await.security.password_secret.is_empty() {
+            ComponentHealth::fail("No password secret is configured.")
+        } else {
+            ComponentHealth::ok()
+        },
+    );
+
+    let redis = redis.lock().await.clone();
+    report.add("redis", check_redis(&redis).await);
+
+    Ok(health(&report)?)
+}
+
+// Escape a string for a quoted-string of an HTTP header (RFC 9110).
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use min_auth_common::config::redis::LoginAlias;
+
+    #[test]
+    fn test_escape_quoted() {
+        assert_eq!(escape_quoted("Service 1"), "Service 1");
+        assert_eq!(escape_quoted(r#"a "b" c"#), r#"a \"b\" c"#);
+        assert_eq!(escape_quoted(r"a\b"), r"a\\b");
+        assert_eq!(escape_quoted(r#"\""#), r#"\\\""#);
+    }
+
+    #[test]
+    fn test_reused_totp() {
+        // Two Basic requests with the same code, the second finding the first
+        let credential = hash_token("secret", "foo1:password123456:123456");
+        assert!(reused_totp("Foo1", Some(&credential), Some(&credential)).is_ok());
+        // Another credential, or a login after a Basic request
+        let other = hash_token("secret", "foo1:password654321:123456");
+        for (credential, first) in [
+            (Some(other.as_str()), Some(credential.as_str())),
+            (None, Some(credential.as_str())),
+            (Some(credential.as_str()), Some(TOTP_USED)),
+            (Some(credential.as_str()), None),
+        ] {
+            let e = reused_totp("Foo1", credential, first).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::BadTotp);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_user() {
+        // Nothing listens on the port, so a lookup fails on the connection.
+        let redis = Arc::new(Mutex::new(
+            RedisClient::open("redis://127.0.0.1:1/0").unwrap(),
+        ));
+        let metrics = Arc::new(Metrics::default());
+        let keys = Keyspace::default().with_aliases(&LoginAlias::ALL);
+        for login in ["token:abc", "username:foo1", ":"] {
+            let e = find_user(&login.to_string(), &keys, &redis, &metrics)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(e.kind(), ErrorKind::UnknownUser, "{}", login);
+        }
+        let e = find_user(&"foo1".to_string(), &keys, &redis, &metrics)
+            .await
+            .err()
+            .unwrap();
+        assert_ne!(e.kind(), ErrorKind::UnknownUser);
+    }
+
+    #[test]
+    fn test_directory_totp() {
+        // Not required for the service
+        assert!(directory_totp("ldap1", &None::<()>).is_ok());
+        let e = directory_totp("ldap1", &Some(())).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TotpRequired);
+    }
 }
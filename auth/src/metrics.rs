@@ -0,0 +1,346 @@
+use crate::audit::{AuditRecord, Decision};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    body::Incoming, header, service::Service as HyperService, Method, Request, Response, StatusCode,
+};
+use min_auth_common::{config::auth::AuthConfig, DynError};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+// Upper bounds (seconds) of the Redis latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+// The label of services not named in the config
+const OTHER_SERVICE: &str = "other";
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // Services labeled by name. The query is sent by clients, so
+    // labeling any service would let them grow the metrics without bound.
+    services: BTreeSet<String>,
+    // (service, decision, reason) -> count
+    decisions: Mutex<BTreeMap<(String, &'static str, &'static str), u64>>,
+    redis_latency: Mutex<Histogram>,
+    active_connections: AtomicI64,
+    in_flight_requests: AtomicI64,
+    // Requests answered with 503 because of `max_in_flight`
+    shed_requests: AtomicU64,
+    // Connections closed because of `max_connections`
+    rejected_connections: AtomicU64,
+}
+
+// Decrements the active connection gauge when the connection is closed.
+pub struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+}
+
+// Decrements the in-flight request gauge when the response is ready.
+pub struct RequestGuard {
+    metrics: Arc<Metrics>,
+}
+
+impl Metrics {
+    // Services named in the realms, the TOTP policy or the LDAP groups
+    pub fn new(config: &AuthConfig) -> Self {
+        let mut services: BTreeSet<String> = config.realm.services.keys().cloned().collect();
+        if let Some(totp) = &config.totp {
+            services.extend(totp.services.iter().cloned());
+        }
+        if let Some(ldap) = &config.ldap {
+            services.extend(ldap.groups.values().flatten().cloned());
+        }
+        services.remove("*");
+        Self {
+            services,
+            ..Default::default()
+        }
+    }
+
+    pub fn observe_decision(&self, record: &AuditRecord) {
+        let decision = match record.decision {
+            Decision::Allow => "allow",
+            Decision::Deny => "deny",
+        };
+        let service = match record.service.as_deref() {
+            Some(service) if self.services.contains(service) => service.to_string(),
+            Some(_) => OTHER_SERVICE.to_string(),
+            None => String::new(),
+        };
+        let mut decisions = match self.decisions.lock() {
+            Ok(decisions) => decisions,
+            Err(e) => e.into_inner(),
+        };
+        *decisions
+            .entry((service, decision, record.reason))
+            .or_default() += 1;
+    }
+
+    pub fn observe_redis_latency(&self, elapsed: Duration) {
+        let elapsed = elapsed.as_secs_f64();
+        let mut histogram = match self.redis_latency.lock() {
+            Ok(histogram) => histogram,
+            Err(e) => e.into_inner(),
+        };
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if elapsed <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+        histogram.sum += elapsed;
+        histogram.count += 1;
+    }
+
+    pub fn connect(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: Arc::clone(self),
+        }
+    }
+
+    pub fn request(self: &Arc<Self>) -> RequestGuard {
+        self.in_flight_requests.fetch_add(1, Ordering::Relaxed);
+        RequestGuard {
+            metrics: Arc::clone(self),
+        }
+    }
+
+    pub fn shed_request(&self) {
+        self.shed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reject_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Render all metrics in the Prometheus text exposition format (0.0.4).
+    pub fn render(&self) -> String {
+        let mut ret = String::new();
+
+        let _ = writeln!(
+            ret,
+            "# HELP min_auth_decisions_total Authentication decisions."
+        );
+        let _ = writeln!(ret, "# TYPE min_auth_decisions_total counter");
+        {
+            let decisions = match self.decisions.lock() {
+                Ok(decisions) => decisions,
+                Err(e) => e.into_inner(),
+            };
+            for ((service, decision, reason), count) in decisions.iter() {
+                let _ = writeln!(
+                    ret,
+                    "min_auth_decisions_total{{service=\"{}\",decision=\"{}\",reason=\"{}\"}} {}",
+                    escape_label(service),
+                    decision,
+                    reason,
+                    count
+                );
+            }
+        }
+
+        let _ = writeln!(
+            ret,
+            "# HELP min_auth_redis_latency_seconds Latency of credential lookups in Redis."
+        );
+        let _ = writeln!(ret, "# TYPE min_auth_redis_latency_seconds histogram");
+        {
+            let histogram = match self.redis_latency.lock() {
+                Ok(histogram) => histogram,
+                Err(e) => e.into_inner(),
+            };
+            for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                let _ = writeln!(
+                    ret,
+                    "min_auth_redis_latency_seconds_bucket{{le=\"{}\"}} {}",
+                    bound, histogram.buckets[i]
+                );
+            }
+            let _ = writeln!(
+                ret,
+                "min_auth_redis_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(ret, "min_auth_redis_latency_seconds_sum {}", histogram.sum);
+            let _ = writeln!(
+                ret,
+                "min_auth_redis_latency_seconds_count {}",
+                histogram.count
+            );
+        }
+
+        let _ = writeln!(
+            ret,
+            "# HELP min_auth_active_connections Connections currently open."
+        );
+        let _ = writeln!(ret, "# TYPE min_auth_active_connections gauge");
+        let _ = writeln!(
+            ret,
+            "min_auth_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            ret,
+            "# HELP min_auth_in_flight_requests Requests currently processed."
+        );
+        let _ = writeln!(ret, "# TYPE min_auth_in_flight_requests gauge");
+        let _ = writeln!(
+            ret,
+            "min_auth_in_flight_requests {}",
+            self.in_flight_requests.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            ret,
+            "# HELP min_auth_shed_requests_total Requests rejected with 503 while overloaded."
+        );
+        let _ = writeln!(ret, "# TYPE min_auth_shed_requests_total counter");
+        let _ = writeln!(
+            ret,
+            "min_auth_shed_requests_total {}",
+            self.shed_requests.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            ret,
+            "# HELP min_auth_rejected_connections_total Connections closed at the connection limit."
+        );
+        let _ = writeln!(ret, "# TYPE min_auth_rejected_connections_total counter");
+        let _ = writeln!(
+            ret,
+            "min_auth_rejected_connections_total {}",
+            self.rejected_connections.load(Ordering::Relaxed)
+        );
+
+        ret
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .in_flight_requests
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsService {
+    pub metrics: Arc<Metrics>,
+}
+
+impl HyperService<Request<Incoming>> for MetricsService {
+    type Response = Response<Full<Bytes>>;
+    type Error = DynError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let metrics = Arc::clone(&self.metrics);
+
+        Box::pin(async move {
+            match (req.method(), req.uri().path()) {
+                (&Method::GET, "/metrics") => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(metrics.render().into_bytes().into())?),
+                _ => Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body("".to_string().into_bytes().into())?),
+            }
+        })
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> Metrics {
+        Metrics {
+            services: BTreeSet::from(["service 1".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    fn record(service: Option<&str>, decision: Decision, reason: &'static str) -> AuditRecord {
+        AuditRecord {
+            service: service.map(|service| service.to_string()),
+            decision,
+            reason,
+            ..AuditRecord::new(None)
+        }
+    }
+
+    #[test]
+    fn test_decisions() {
+        let metrics = metrics();
+        metrics.observe_decision(&record(Some("service 1"), Decision::Allow, "ok"));
+        metrics.observe_decision(&record(Some("service 1"), Decision::Allow, "ok"));
+        metrics.observe_decision(&record(Some("x1"), Decision::Deny, "bad_password"));
+        metrics.observe_decision(&record(Some("x2"), Decision::Deny, "bad_password"));
+        metrics.observe_decision(&record(None, Decision::Deny, "bad_request"));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "min_auth_decisions_total{service=\"service 1\",decision=\"allow\",reason=\"ok\"} 2\n"
+        ));
+        // Services not in the config share a label.
+        assert!(rendered.contains(
+            "min_auth_decisions_total{service=\"other\",decision=\"deny\",reason=\"bad_password\"} 2\n"
+        ));
+        assert!(rendered.contains(
+            "min_auth_decisions_total{service=\"\",decision=\"deny\",reason=\"bad_request\"} 1\n"
+        ));
+        assert!(!rendered.contains("x1"));
+        assert!(rendered.contains("# TYPE min_auth_decisions_total counter\n"));
+    }
+
+    #[test]
+    fn test_redis_latency() {
+        let metrics = metrics();
+        metrics.observe_redis_latency(Duration::from_millis(3));
+        metrics.observe_redis_latency(Duration::from_secs(3));
+
+        let rendered = metrics.render();
+        // Buckets are cumulative.
+        assert!(rendered.contains("min_auth_redis_latency_seconds_bucket{le=\"0.0025\"} 0\n"));
+        assert!(rendered.contains("min_auth_redis_latency_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("min_auth_redis_latency_seconds_bucket{le=\"2.5\"} 1\n"));
+        assert!(rendered.contains("min_auth_redis_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("min_auth_redis_latency_seconds_sum 3.003\n"));
+        assert!(rendered.contains("min_auth_redis_latency_seconds_count 2\n"));
+    }
+}
@@ -0,0 +1,154 @@
+use hyper::HeaderMap;
+use min_auth_common::{
+    config::auth::MtlsConfig,
+    error::{Error, ErrorKind},
+};
+use std::collections::HashMap;
+
+// Values of $ssl_client_verify
+const VERIFIED: &str = "SUCCESS";
+const NOT_SENT: &str = "NONE";
+
+// Maps client certificates forwarded by NGINX to user IDs.
+#[derive(Debug)]
+pub struct CertificateMapper {
+    config: MtlsConfig,
+    // Normalized fingerprints to user IDs
+    fingerprints: HashMap<String, String>,
+}
+
+impl CertificateMapper {
+    pub fn new(config: &MtlsConfig) -> Self {
+        Self {
+            config: config.clone(),
+            fingerprints: config
+                .fingerprints
+                .iter()
+                .map(|(fingerprint, user_id)| (normalize(fingerprint), user_id.clone()))
+                .collect(),
+        }
+    }
+
+    // Returns None if no client certificate was sent.
+    // A fingerprint takes precedence over a subject DN.
+    pub fn user_id(&self, headers: &HeaderMap) -> Result<Option<String>, Error> {
+        let verify = match header(headers, &self.config.verify_header)? {
+            Some(verify) if !verify.is_empty() && verify != NOT_SENT => verify,
+            _ => return Ok(None),
+        };
+        if verify != VERIFIED {
+            return Err(Error::new(
+                ErrorKind::InvalidCertificate,
+                format!("The client certificate was not verified ({}).", verify),
+            ));
+        }
+
+        let fingerprint = header(headers, &self.config.fingerprint_header)?;
+        if let Some(user_id) = fingerprint
+            .as_ref()
+            .and_then(|fingerprint| self.fingerprints.get(&normalize(fingerprint)))
+        {
+            return Ok(Some(user_id.clone()));
+        }
+        let subject = header(headers, &self.config.subject_header)?;
+        if let Some(user_id) = subject
+            .as_ref()
+            .and_then(|subject| self.config.subjects.get(subject))
+        {
+            return Ok(Some(user_id.clone()));
+        }
+
+        Err(Error::new(
+            ErrorKind::UnknownCertificate,
+            format!(
+                "No user is mapped to the client certificate (subject: {}, fingerprint: {}).",
+                subject.unwrap_or_default(),
+                fingerprint.unwrap_or_default()
+            ),
+        ))
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Result<Option<String>, Error> {
+    match headers.get(name) {
+        Some(value) => match value.to_str() {
+            Ok(value) => Ok(Some(value.trim().to_string())),
+            Err(e) => Err(Error::new(ErrorKind::MalformedHeader, e)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn normalize(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn mapper() -> CertificateMapper {
+        CertificateMapper::new(&MtlsConfig {
+            verify_header: "X-SSL-Client-Verify".to_string(),
+            subject_header: "X-SSL-Client-S-DN".to_string(),
+            fingerprint_header: "X-SSL-Client-Fingerprint".to_string(),
+            fingerprints: HashMap::from([(
+                "01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67".to_string(),
+                "ci".to_string(),
+            )]),
+            subjects: HashMap::from([("CN=deploy,O=Example".to_string(), "deploy".to_string())]),
+        })
+    }
+
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_user_id() {
+        let mapper = mapper();
+
+        let ret = mapper.user_id(&headers(&[
+            ("x-ssl-client-verify", "SUCCESS"),
+            ("x-ssl-client-s-dn", "CN=deploy,O=Example"),
+            (
+                "x-ssl-client-fingerprint",
+                "0123456789abcdef0123456789abcdef01234567",
+            ),
+        ]));
+        assert_eq!(ret.unwrap(), Some("ci".to_string()));
+
+        let ret = mapper.user_id(&headers(&[
+            ("x-ssl-client-verify", "SUCCESS"),
+            ("x-ssl-client-s-dn", "CN=deploy,O=Example"),
+            ("x-ssl-client-fingerprint", "ffff"),
+        ]));
+        assert_eq!(ret.unwrap(), Some("deploy".to_string()));
+
+        // No certificate
+        assert_eq!(mapper.user_id(&headers(&[])).unwrap(), None);
+        let ret = mapper.user_id(&headers(&[("x-ssl-client-verify", "NONE")]));
+        assert_eq!(ret.unwrap(), None);
+
+        let e = mapper
+            .user_id(&headers(&[
+                ("x-ssl-client-verify", "FAILED:certificate has expired"),
+                ("x-ssl-client-s-dn", "CN=deploy,O=Example"),
+            ]))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidCertificate);
+
+        let e = mapper
+            .user_id(&headers(&[
+                ("x-ssl-client-verify", "SUCCESS"),
+                ("x-ssl-client-s-dn", "CN=other,O=Example"),
+            ]))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnknownCertificate);
+    }
+}
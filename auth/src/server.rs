@@ -0,0 +1,312 @@
+use crate::{metrics::MetricsService, Service};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use min_auth_common::{
+    config::auth::HttpConfig,
+    error::{Error, ErrorKind},
+    DynError,
+};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+// hyper panics with a smaller HTTP/1 buffer.
+const MIN_HEADER_SIZE: usize = 8192;
+
+// A builder serving both HTTP/1.1 and HTTP/2 with the limits in the config.
+pub fn builder(config: &HttpConfig) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .keep_alive(config.keep_alive)
+        .header_read_timeout(seconds(config.header_read_timeout))
+        .max_buf_size(config.max_header_size.max(MIN_HEADER_SIZE));
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(seconds(config.keep_alive_interval))
+        .max_header_list_size(u32::try_from(config.max_header_size).unwrap_or(u32::MAX));
+    builder
+}
+
+// Limits shared by the connections of a socket
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    connections: Option<Arc<Semaphore>>,
+    in_flight: Option<Arc<Semaphore>>,
+    // For the TLS handshake and the first request
+    idle_timeout: Option<Duration>,
+}
+
+impl Limits {
+    pub fn new(config: &HttpConfig) -> Self {
+        let semaphore = |max: usize| (max > 0).then(|| Arc::new(Semaphore::new(max)));
+        Self {
+            connections: semaphore(config.max_connections),
+            in_flight: semaphore(config.max_in_flight),
+            idle_timeout: seconds(config.header_read_timeout),
+        }
+    }
+
+    // Fails at once at the limit, so that the connection is closed instead of
+    // blocking the accept loop behind clients that never send anything.
+    pub fn connection(&self) -> Result<Option<OwnedSemaphorePermit>, Error> {
+        match &self.connections {
+            Some(connections) => match Arc::clone(connections).try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err(Error::new(
+                    ErrorKind::Unavailable,
+                    "Too many connections are open.",
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // hyper has no timer until it knows the HTTP version, so the steps before it are limited here.
+    pub async fn idle<F, T>(&self, future: F) -> Result<T, Error>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        match self.idle_timeout {
+            Some(wait) => match timeout(wait, future).await {
+                Ok(ret) => Ok(ret?),
+                Err(_) => Err(Error::new(
+                    ErrorKind::BadRequest,
+                    "The client sent nothing in time.",
+                )),
+            },
+            None => Ok(future.await?),
+        }
+    }
+
+    // Fails at once while overloaded rather than queueing the request.
+    pub fn request(&self) -> Result<Option<OwnedSemaphorePermit>, Error> {
+        match &self.in_flight {
+            Some(in_flight) => match Arc::clone(in_flight).try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err(Error::new(
+                    ErrorKind::Unavailable,
+                    "Too many requests are in flight.",
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+// Counts requests on a connection to close it after `max_requests`.
+#[derive(Debug, Default)]
+pub struct RequestCounter {
+    limit: u64,
+    count: AtomicU64,
+    reached: Notify,
+}
+
+impl RequestCounter {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    pub fn count(&self) {
+        if self.limit > 0 && self.count.fetch_add(1, Ordering::Relaxed) + 1 == self.limit {
+            self.reached.notify_one();
+        }
+    }
+}
+
+// Replays the bytes read while waiting for the first request.
+#[derive(Debug)]
+pub struct Rewind<IO> {
+    prefix: Vec<u8>,
+    pos: usize,
+    io: IO,
+}
+
+impl<IO> Rewind<IO>
+where
+    IO: AsyncRead + Unpin,
+{
+    pub async fn read(mut io: IO) -> io::Result<Self> {
+        let mut prefix = vec![0; MIN_HEADER_SIZE];
+        let len = io.read(&mut prefix).await?;
+        prefix.truncate(len);
+        Ok(Self { prefix, pos: 0, io })
+    }
+}
+
+impl<IO> AsyncRead for Rewind<IO>
+where
+    IO: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO> AsyncWrite for Rewind<IO>
+where
+    IO: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+// Serve a connection until the client closes it or the request limit is reached.
+// In-flight requests are completed before closing.
+pub async fn serve<IO>(
+    builder: &auto::Builder<TokioExecutor>,
+    io: IO,
+    svc: Service,
+) -> Result<(), DynError>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = svc.limits.idle(Rewind::read(io)).await?;
+    let requests = svc.requests.clone();
+    let conn = builder.serve_connection(TokioIo::new(io), svc);
+    tokio::pin!(conn);
+    tokio::select! {
+        ret = conn.as_mut() => ret,
+        _ = requests.reached.notified() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    }
+}
+
+// Serve a metrics connection with the same limits as the service sockets.
+pub async fn serve_metrics<IO>(
+    builder: &auto::Builder<TokioExecutor>,
+    limits: &Limits,
+    io: IO,
+    svc: MetricsService,
+) -> Result<(), DynError>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = limits.idle(Rewind::read(io)).await?;
+    builder.serve_connection(TokioIo::new(io), svc).await
+}
+
+fn seconds(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits::new(&HttpConfig {
+            max_in_flight: 1,
+            ..Default::default()
+        });
+        let permit = limits.request().unwrap();
+        assert!(permit.is_some());
+        let e = limits.request().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unavailable);
+        drop(permit);
+        assert!(limits.request().is_ok());
+
+        // No limit
+        let limits = Limits::new(&HttpConfig {
+            max_in_flight: 0,
+            ..Default::default()
+        });
+        assert!(limits.request().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections() {
+        let limits = Limits::new(&HttpConfig {
+            max_connections: 2,
+            header_read_timeout: 1,
+            ..Default::default()
+        });
+
+        // Idle clients hold every permit until they time out.
+        let mut idle = vec![];
+        for _ in 0..2 {
+            let (client, server) = duplex(64);
+            let permit = limits.connection().unwrap();
+            idle.push((client, server, permit));
+        }
+        // New connections are refused instead of waiting for them.
+        let e = limits.connection().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unavailable);
+
+        let (_client, server, permit) = idle.pop().unwrap();
+        let e = limits.idle(Rewind::read(server)).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadRequest);
+        drop(permit);
+        assert!(limits.connection().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rewind() {
+        let (mut client, server) = duplex(64);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut rewind = Rewind::read(server).await.unwrap();
+        client.write_all(b"\r\n").await.unwrap();
+        drop(client);
+
+        let mut received = vec![];
+        rewind.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_request_counter() {
+        let counter = RequestCounter::new(2);
+        counter.count();
+        counter.count();
+        // The permit is stored even before waiting.
+        counter.reached.notified().await;
+    }
+}
@@ -0,0 +1,219 @@
+use aes_gcm::{Aes256Gcm, Key as AesKey, KeyInit};
+use hyper::{header, HeaderMap};
+use min_auth_common::{
+    config::auth::SessionConfig,
+    error::{Error, ErrorKind},
+    utils::{genid::genid, seal},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+// Refresh the cookie at most once in this period (seconds) to keep
+// the idle timer going without rewriting it on every request.
+const REFRESH_INTERVAL: i64 = 60;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    // Unix time in seconds
+    pub issued_at: i64,
+    pub last_seen: i64,
+    // Logged in with a TOTP code as well
+    #[serde(default)]
+    pub totp: bool,
+}
+
+// Seals sessions into cookies with AES-256-GCM and opens them again.
+pub struct SessionCodec {
+    cipher: Aes256Gcm,
+    config: SessionConfig,
+}
+
+impl Session {
+    pub fn new<U>(user_id: U, totp: bool, now: i64) -> Self
+    where
+        U: ToString,
+    {
+        Self {
+            id: genid(),
+            user_id: user_id.to_string(),
+            issued_at: now,
+            last_seen: now,
+            totp,
+        }
+    }
+}
+
+impl SessionCodec {
+    pub fn new(config: &SessionConfig) -> Result<Self, Error> {
+        let key = hex::decode(&config.key).map_err(|e| Error::new(ErrorKind::InvalidConfig, e))?;
+        if key.len() != 32 {
+            return Err(Error::new(
+                ErrorKind::InvalidConfig,
+                "The session key must be 32 bytes in hex.",
+            ));
+        }
+        Ok(Self {
+            cipher: Aes256Gcm::new(AesKey::<Aes256Gcm>::from_slice(&key)),
+            config: config.clone(),
+        })
+    }
+
+    pub fn seal(&self, session: &Session) -> Result<String, Error> {
+        let aad = self.config.cookie_name.as_bytes();
+        Ok(hex::encode(seal::seal(&self.cipher, session, aad)?))
+    }
+
+    pub fn open(&self, value: &str, now: i64) -> Result<Session, Error> {
+        let sealed = hex::decode(value).map_err(|e| Error::new(ErrorKind::InvalidSession, e))?;
+        let session: Session =
+            seal::open(&self.cipher, &sealed, self.config.cookie_name.as_bytes())?;
+
+        if now - session.issued_at >= self.config.absolute_timeout as i64 {
+            return Err(Error::new(
+                ErrorKind::ExpiredSession,
+                format!("Session {} of {} has expired.", session.id, session.user_id),
+            ));
+        }
+        if now - session.last_seen >= self.config.idle_timeout as i64 {
+            return Err(Error::new(
+                ErrorKind::ExpiredSession,
+                format!(
+                    "Session {} of {} has been idle.",
+                    session.id, session.user_id
+                ),
+            ));
+        }
+        Ok(session)
+    }
+
+    // Returns a refreshed session if it is time to update the cookie.
+    pub fn touch(&self, session: &Session, now: i64) -> Option<Session> {
+        if now - session.last_seen < REFRESH_INTERVAL {
+            return None;
+        }
+        Some(Session {
+            last_seen: now,
+            ..session.clone()
+        })
+    }
+
+    // Seconds until the session reaches the absolute timeout
+    pub fn remaining(&self, session: &Session, now: i64) -> i64 {
+        session.issued_at + self.config.absolute_timeout as i64 - now
+    }
+
+    pub fn find(&self, headers: &HeaderMap) -> Option<String> {
+        for cookies in headers.get_all(header::COOKIE) {
+            let cookies = match cookies.to_str() {
+                Ok(cookies) => cookies,
+                Err(_) => continue,
+            };
+            for cookie in cookies.split(';') {
+                if let Some((name, value)) = cookie.trim().split_once('=') {
+                    if name == self.config.cookie_name {
+                        return Some(value.to_string());
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // A Set-Cookie value
+    pub fn cookie(&self, value: &str) -> String {
+        self.build_cookie(value, self.config.absolute_timeout as i64)
+    }
+
+    // A Set-Cookie value removing the cookie
+    pub fn clear_cookie(&self) -> String {
+        self.build_cookie("", 0)
+    }
+
+    fn build_cookie(&self, value: &str, max_age: i64) -> String {
+        let mut ret = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.config.cookie_name, value, max_age
+        );
+        if let Some(domain) = &self.config.cookie_domain {
+            ret.push_str(&format!("; Domain={}", domain));
+        }
+        if self.config.secure {
+            ret.push_str("; Secure");
+        }
+        ret
+    }
+}
+
+impl Debug for SessionCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCodec")
+            .field("cookie_name", &self.config.cookie_name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn codec() -> SessionCodec {
+        SessionCodec::new(&SessionConfig {
+            key: "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff".to_string(),
+            cookie_name: "min-auth".to_string(),
+            cookie_domain: Some("example.com".to_string()),
+            secure: true,
+            idle_timeout: 600,
+            absolute_timeout: 3600,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let codec = codec();
+        let session = Session::new("user-1", false, 1000);
+        let value = codec.seal(&session).unwrap();
+        assert_eq!(codec.open(&value, 1000).unwrap(), session);
+
+        // Tampered
+        let mut tampered = hex::decode(&value).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let e = codec.open(&hex::encode(tampered), 1000).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidSession);
+        let e = codec.open("xyz", 1000).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidSession);
+
+        // Idle
+        let e = codec.open(&value, 1600).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ExpiredSession);
+
+        // Touched in time, but too old
+        let touched = codec.touch(&session, 1500).unwrap();
+        assert!(codec.touch(&touched, 1530).is_none());
+        let value = codec.seal(&touched).unwrap();
+        assert_eq!(codec.open(&value, 2000).unwrap(), touched);
+        let e = codec.open(&value, 4600).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ExpiredSession);
+        assert_eq!(codec.remaining(&touched, 4000), 600);
+    }
+
+    #[test]
+    fn test_cookie() {
+        let codec = codec();
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::COOKIE,
+            HeaderValue::from_static("a=1; min-auth=abc"),
+        );
+        assert_eq!(codec.find(&headers), Some("abc".to_string()));
+        assert_eq!(
+            codec.cookie("abc"),
+            "min-auth=abc; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax; Domain=example.com; Secure"
+        );
+        assert_eq!(codec.find(&HeaderMap::new()), None);
+    }
+}
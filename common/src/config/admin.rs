@@ -1,4 +1,7 @@
-use crate::DynError;
+use crate::{
+    config::{redis::RecordEncoding, socket::SocketConfig},
+    error::Error,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{read_to_string, File},
@@ -12,17 +15,26 @@ pub struct AdminConfig {
     pub redis: RedisConfig,
     pub security: SecurityConfig,
     pub file_system: FsConfig,
+    pub webauthn: Option<WebAuthnConfig>,
+    // Compare the auth Redis with the user directory periodically.
+    pub drift: Option<DriftConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ExposeConfig {
-    pub sockets: Vec<String>,
+    // Each socket serves HTTPS if `tls` is specified.
+    pub sockets: Vec<SocketConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RedisConfig {
     pub session: String,
     pub auth: Vec<String>,
+    // Prepended to the keys of users and tokens in the auth Redis
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub encoding: RecordEncoding,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -34,10 +46,48 @@ pub struct SecurityConfig {
 pub struct FsConfig {
     pub users: String,
     pub requests: String,
+    // Fail instead of skipping files that cannot be loaded.
+    #[serde(default)]
+    pub strict: bool,
+    // Commit every applied change if `users` is in a git repository.
+    pub git: Option<GitConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GitConfig {
+    // The committer if not configured in the repository
+    pub name: Option<String>,
+    pub email: Option<String>,
+    // Sign the commits with this GPG key.
+    pub signing_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebAuthnConfig {
+    // The domain of the admin UI (e.g. "admin.example.com")
+    pub rp_id: String,
+    pub rp_name: String,
+    // The origin the browser reports (e.g. "https://admin.example.com")
+    pub origin: String,
+    // Require the authenticator to verify the user (PIN or biometrics).
+    #[serde(default)]
+    pub user_verification: bool,
+    // Allow superusers to log in with a passkey only.
+    #[serde(default)]
+    pub passwordless: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DriftConfig {
+    // Seconds between checks
+    pub interval: u64,
+    // Overwrite the auth Redis with the user directory when drift is found.
+    #[serde(default)]
+    pub repair: bool,
 }
 
 impl AdminConfig {
-    pub fn load<P>(path: P) -> Result<Self, DynError>
+    pub fn load<P>(path: P) -> Result<Self, Error>
     where
         P: AsRef<Path>,
     {
@@ -45,7 +95,7 @@ impl AdminConfig {
         Ok(content.as_str().try_into()?)
     }
 
-    pub fn save<P>(&self, path: P) -> Result<(), DynError>
+    pub fn save<P>(&self, path: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
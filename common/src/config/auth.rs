@@ -1,6 +1,13 @@
-use crate::DynError;
+use crate::{
+    config::{
+        redis::{LoginAlias, RecordEncoding},
+        socket::SocketConfig,
+    },
+    error::Error,
+};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fs::{read_to_string, File},
     io::Write,
     path::Path,
@@ -11,25 +18,216 @@ pub struct AuthConfig {
     pub expose: ExposeConfig,
     pub security: SecurityConfig,
     pub redis: RedisConfig,
+    #[serde(default)]
+    pub realm: RealmConfig,
+    pub audit: Option<AuditConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub session: Option<SessionConfig>,
+    pub totp: Option<TotpConfig>,
+    pub mtls: Option<MtlsConfig>,
+    pub ldap: Option<LdapConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ExposeConfig {
-    pub sockets: Vec<String>,
+    // Each socket serves HTTPS if `tls` is specified.
+    pub sockets: Vec<SocketConfig>,
+    // A socket exporting metrics in the Prometheus text format
+    pub metrics: Option<String>,
+    #[serde(default)]
+    pub http: HttpConfig,
+}
+
+// Connection limits against slow or greedy clients.
+// HTTP/1.1 and HTTP/2 (h2c or ALPN) are both served.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HttpConfig {
+    // Keep HTTP/1.1 connections open between requests.
+    pub keep_alive: bool,
+    // Seconds between HTTP/2 pings; 0 disables them.
+    pub keep_alive_interval: u64,
+    // Seconds allowed for the TLS handshake and the first request of a connection,
+    // and to send the headers of each request
+    pub header_read_timeout: u64,
+    // Bytes allowed for the headers of a request (at least 8192)
+    pub max_header_size: usize,
+    // Requests served on a connection before it is closed; 0 means no limit.
+    pub max_requests: u64,
+    // Open connections per socket; more are closed at once. 0 means no limit.
+    pub max_connections: usize,
+    // Requests processed at once per socket; more are answered with 503. 0 means no limit.
+    pub max_in_flight: usize,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SecurityConfig {
     pub password_secret: String,
+    // Identifiers accepted at login besides the user ID
+    #[serde(default)]
+    pub login_aliases: Vec<LoginAlias>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RedisConfig {
     pub uri: String,
+    // Prepended to every key read or written, which must match min-auth-admin
+    #[serde(default)]
+    pub key_prefix: String,
+    #[serde(default)]
+    pub encoding: RecordEncoding,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RealmConfig {
+    // The realm for services not listed in `services`.
+    pub default: String,
+    // Service name -> realm
+    #[serde(default)]
+    pub services: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditConfig {
+    // JSON lines are appended to this file if specified.
+    pub path: Option<String>,
+    // JSON lines are also sent to the local syslog daemon (/dev/log) if true.
+    pub syslog: bool,
+    // The ratio of successful decisions to be recorded (0.0 - 1.0).
+    // Failures are always recorded.
+    pub success_sample_rate: f64,
+    // Record the X-Real-IP header instead of the peer address.
+    // Enable only if every client connects through a proxy setting it.
+    #[serde(default)]
+    pub trust_real_ip: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JwtConfig {
+    // Paths to JWKS files holding RS256, ES256 or EdDSA public keys
+    pub jwks: Vec<String>,
+    // At least one of them must be in the "aud" claim.
+    pub audiences: Vec<String>,
+    // The "iss" claim must be one of them if not empty.
+    #[serde(default)]
+    pub issuers: Vec<String>,
+    // The claim holding a min-auth user ID (e.g. "sub")
+    pub user_claim: String,
+    // Allowed clock skew in seconds for "exp" and "nbf"
+    #[serde(default)]
+    pub leeway: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionConfig {
+    // A 256-bit AES-GCM key in hex, shared by all auth instances
+    pub key: String,
+    pub cookie_name: String,
+    pub cookie_domain: Option<String>,
+    // Send the cookie only over HTTPS.
+    pub secure: bool,
+    // Seconds without any request until a session expires
+    pub idle_timeout: u64,
+    // Seconds from login until a session expires
+    pub absolute_timeout: u64,
+}
+
+// Bearer tokens, JWTs and client certificates are not affected
+// as they are for machines.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpConfig {
+    // Services requiring a TOTP code ("*" for all of them)
+    pub services: Vec<String>,
+    // A header holding the code. Without it, the code is appended to the password.
+    pub header: String,
+    // Steps accepted before and after the current one
+    #[serde(default)]
+    pub skew: u64,
+}
+
+// Client certificates verified by NGINX. The headers must be set by NGINX
+// only, e.g. proxy_set_header X-SSL-Client-Verify $ssl_client_verify;
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MtlsConfig {
+    // $ssl_client_verify
+    pub verify_header: String,
+    // $ssl_client_s_dn
+    pub subject_header: String,
+    // $ssl_client_fingerprint
+    pub fingerprint_header: String,
+    // SHA-1 fingerprints in hex (colons are ignored) to user IDs
+    #[serde(default)]
+    pub fingerprints: HashMap<String, String>,
+    // Subject DNs in RFC 2253 (e.g. "CN=ci,O=Example") to user IDs
+    #[serde(default)]
+    pub subjects: HashMap<String, String>,
+}
+
+// Basic credentials of users not in Redis are verified by binding to an LDAP server.
+// Their password is not split as in `TotpConfig`, and services requiring TOTP refuse them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LdapConfig {
+    // "ldap://host:389" or "ldaps://host:636"
+    pub url: String,
+    // Upgrade "ldap://" connections with StartTLS.
+    #[serde(default)]
+    pub starttls: bool,
+    // The DN to bind as, "{}" is replaced with the user ID
+    // (e.g. "uid={},ou=people,dc=example,dc=com").
+    pub bind_dn: String,
+    // Groups are searched under `group_base` with `group_filter`,
+    // "{}" is replaced with the bound DN (e.g. "(member={})").
+    pub group_base: String,
+    pub group_filter: String,
+    // The attribute holding the group name (e.g. "cn")
+    pub group_attribute: String,
+    // Group name -> services its members are allowed to use
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    // Seconds to wait for the server
+    pub timeout: u64,
+}
+
+impl RealmConfig {
+    pub fn get(&self, service: Option<&str>) -> &str {
+        match service.and_then(|service| self.services.get(service)) {
+            Some(realm) => realm,
+            None => &self.default,
+        }
+    }
+}
+
+impl TotpConfig {
+    pub fn required(&self, service: &str) -> bool {
+        self.services.iter().any(|s| s == "*" || s == service)
+    }
+}
+
+impl Default for RealmConfig {
+    fn default() -> Self {
+        Self {
+            default: "min-auth".to_string(),
+            services: HashMap::new(),
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            keep_alive_interval: 60,
+            header_read_timeout: 10,
+            max_header_size: 16 * 1024,
+            max_requests: 1000,
+            max_connections: 1024,
+            max_in_flight: 256,
+        }
+    }
 }
 
 impl AuthConfig {
-    pub fn load<P>(path: P) -> Result<Self, DynError>
+    pub fn load<P>(path: P) -> Result<Self, Error>
     where
         P: AsRef<Path>,
     {
@@ -37,7 +235,7 @@ impl AuthConfig {
         Ok(content.as_str().try_into()?)
     }
 
-    pub fn save<P>(&self, path: P) -> Result<(), DynError>
+    pub fn save<P>(&self, path: P) -> Result<(), Error>
     where
         P: AsRef<Path>,
     {
@@ -63,3 +261,25 @@ impl TryFrom<&AuthConfig> for String {
         toml::ser::to_string(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_realm() {
+        let realm: RealmConfig = toml::from_str(
+            r#"
+            default = "min-auth"
+            services = { "service 1" = "Service 1" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(realm.get(Some("service 1")), "Service 1");
+        // Unknown or missing services fall back to the default.
+        assert_eq!(realm.get(Some("service 2")), "min-auth");
+        assert_eq!(realm.get(None), "min-auth");
+
+        assert_eq!(RealmConfig::default().get(Some("service 1")), "min-auth");
+    }
+}
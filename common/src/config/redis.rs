@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+// How user records are stored in the auth Redis. Tokens are always JSON.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordEncoding {
+    // A JSON string
+    #[default]
+    Json,
+    // A hash with a field for each item, so that one can be updated alone
+    // (e.g. `HGET <key> acl`). The ACL is a JSON array.
+    Hash,
+}
+
+// An identifier accepted at login besides the user ID. min-auth-admin stores
+// each of them in lower case as a key holding the user ID.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LoginAlias {
+    Username,
+    Email,
+}
+
+impl LoginAlias {
+    pub const ALL: [LoginAlias; 2] = [LoginAlias::Username, LoginAlias::Email];
+
+    pub fn key_prefix(&self) -> &'static str {
+        match self {
+            LoginAlias::Username => "username:",
+            LoginAlias::Email => "email:",
+        }
+    }
+}
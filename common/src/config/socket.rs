@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+// An entry of `expose.sockets`.
+// A plain string keeps serving HTTP, a table with `tls` serves HTTPS.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum SocketConfig {
+    Plain(String),
+    Tls { address: String, tls: TlsConfig },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    // PEM files of the certificate chain and the private key.
+    // They are reloaded when modified.
+    pub cert: String,
+    pub key: String,
+    // Clients must present a certificate issued by one of these PEM CAs if specified.
+    pub client_ca: Option<String>,
+    // Also accept clients without a certificate.
+    #[serde(default)]
+    pub client_auth_optional: bool,
+}
+
+impl SocketConfig {
+    pub fn address(&self) -> &str {
+        match self {
+            SocketConfig::Plain(address) => address,
+            SocketConfig::Tls { address, .. } => address,
+        }
+    }
+
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        match self {
+            SocketConfig::Plain(_) => None,
+            SocketConfig::Tls { tls, .. } => Some(tls),
+        }
+    }
+}
+
+impl From<&str> for SocketConfig {
+    fn from(value: &str) -> Self {
+        SocketConfig::Plain(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Expose {
+        sockets: Vec<SocketConfig>,
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let expose: Expose = toml::from_str(
+            r#"
+            sockets = [
+                "127.0.0.1:50080",
+                { address = "0.0.0.0:50443", tls = { cert = "a.pem", key = "a.key" } },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(expose.sockets[0].address(), "127.0.0.1:50080");
+        assert!(expose.sockets[0].tls().is_none());
+        assert_eq!(expose.sockets[1].address(), "0.0.0.0:50443");
+        let tls = expose.sockets[1].tls().unwrap();
+        assert_eq!(tls.cert, "a.pem");
+        assert!(tls.client_ca.is_none());
+        assert!(!tls.client_auth_optional);
+    }
+}
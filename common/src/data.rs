@@ -1,16 +1,23 @@
+use crate::error::{Error, ErrorKind};
 use fs2::FileExt;
-use serde::de::DeserializeOwned;
+use log::error;
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
     collections::LinkedList,
-    fs::{read_dir, File, ReadDir},
-    io::BufReader,
+    fs::{read_dir, remove_file, rename, File, OpenOptions, ReadDir},
+    io::{BufReader, BufWriter},
     marker::PhantomData,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+// Not a JSON file, so never picked up by `DataFinder`
+const LOCK_FILE: &str = ".lock";
+
 pub mod credentials;
 pub mod requests;
+pub mod tokens;
 pub mod users;
+pub mod webauthn;
 
 pub trait DataLoader
 where
@@ -28,6 +35,112 @@ where
     }
 }
 
+// Writers take an exclusive lock on the directory and replace records by
+// renaming a synced temporary file, so readers never see a partial record.
+pub trait DataSaver
+where
+    Self: Serialize,
+{
+    fn save<P>(&self, path: P) -> std::io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let dir = lock_dir(path)?;
+        write_atomic(path, &dir, self)
+    }
+
+    // Fails if the record already exists.
+    fn save_new<P>(&self, path: P) -> std::io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let dir = lock_dir(path)?;
+        if path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists.", path.display()),
+            ));
+        }
+        write_atomic(path, &dir, self)
+    }
+
+    fn remove<P>(path: P) -> std::io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let dir = lock_dir(path)?;
+        remove_file(path)?;
+        dir.sync_all()
+    }
+}
+
+// The lock is released when the returned directory is dropped.
+fn lock_dir(path: &Path) -> std::io::Result<File> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = File::open(dir)?;
+    dir.lock_exclusive()?;
+    Ok(dir)
+}
+
+fn write_atomic<T>(path: &Path, dir: &File, data: &T) -> std::io::Result<()>
+where
+    T: Serialize + ?Sized,
+{
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a file path.", path.display()),
+            ))
+        }
+    };
+    // Not a JSON file, so never picked up by `DataFinder`
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    let ret = (|| {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer_pretty(&mut writer, data)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        rename(&tmp, path)
+    })();
+    if ret.is_err() {
+        let _ = remove_file(&tmp);
+    }
+    ret?;
+    // Persist the rename itself.
+    dir.sync_all()
+}
+
+// An exclusive lock on a whole tree of records, held across a change that reads
+// other records first (e.g. a username checked against every user). `DataSaver`
+// only locks the directory of a record, so it does not conflict with this lock.
+// The lock is released when dropped.
+pub struct TreeLock {
+    _file: File,
+}
+
+impl TreeLock {
+    pub fn exclusive<P>(data_dir: P) -> std::io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(data_dir.as_ref().join(LOCK_FILE))?;
+        file.lock_exclusive()?;
+        Ok(Self { _file: file })
+    }
+}
+
 pub struct DataFinder<T>
 where
     T: DataLoader,
@@ -55,13 +168,23 @@ where
     }
 }
 
-impl<T> Iterator for DataFinder<T>
+impl<T> DataFinder<T>
 where
     T: DataLoader,
 {
-    type Item = Result<T, std::io::Error>;
+    // Pair each item with the path of its JSON file.
+    pub fn with_paths(self) -> impl Iterator<Item = (PathBuf, Result<T, std::io::Error>)> {
+        let mut finder = self;
+        std::iter::from_fn(move || match finder.next_path()? {
+            Ok(path) => {
+                let data = T::load(&path);
+                Some((path, data))
+            }
+            Err(e) => Some((PathBuf::new(), Err(e))),
+        })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    fn next_path(&mut self) -> Option<Result<PathBuf, std::io::Error>> {
         loop {
             let reader = match self.readers.back_mut() {
                 Some(item) => item,
@@ -103,7 +226,21 @@ where
                 continue;
             }
 
-            return Some(T::load(path));
+            return Some(Ok(path));
+        }
+    }
+}
+
+impl<T> Iterator for DataFinder<T>
+where
+    T: DataLoader,
+{
+    type Item = Result<T, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_path()? {
+            Ok(path) => Some(T::load(path)),
+            Err(e) => Some(Err(e)),
         }
     }
 }
@@ -124,3 +261,51 @@ where
 
     Ok(ret)
 }
+
+// Items loaded from a directory along with the files that failed to load
+#[derive(Debug)]
+pub struct LoadReport<T> {
+    pub items: Vec<T>,
+    pub errors: Vec<(PathBuf, std::io::Error)>,
+}
+
+impl<T> LoadReport<T>
+where
+    T: DataLoader,
+{
+    pub fn load<P>(data_dir: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut report = Self {
+            items: vec![],
+            errors: vec![],
+        };
+        for (path, item) in DataFinder::<T>::new(data_dir.as_ref())?.with_paths() {
+            match item {
+                Ok(item) => report.items.push(item),
+                Err(e) => report.errors.push((path, e)),
+            }
+        }
+        Ok(report)
+    }
+
+    // Every error is logged, and any of them fails in strict mode.
+    pub fn into_items(self, strict: bool) -> Result<Vec<T>, Error> {
+        for (path, e) in &self.errors {
+            error!("{} failed to load: {}", path.display(), e);
+        }
+        match (strict, self.errors.first()) {
+            (true, Some((path, e))) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} files failed to load (e.g. {}: {}).",
+                    self.errors.len(),
+                    path.display(),
+                    e
+                ),
+            )),
+            _ => Ok(self.items),
+        }
+    }
+}
@@ -1,16 +1,34 @@
-use super::users::{AccessControl, AccessControlKind};
-use crate::utils::get_hash;
+use super::{
+    tokens::token_key,
+    users::{AccessControl, User},
+};
+use crate::{
+    config::redis::{LoginAlias, RecordEncoding},
+    utils::get_hash,
+};
 use log::debug;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Serialize};
 use serde_json::json;
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display};
+
+// The schema version of `Credentials` written by this version
+pub const CREDENTIALS_VERSION: u32 = 1;
+
+// Keys the auth server writes itself, which are not records
+const TOTP_USED_KEY_PREFIX: &str = "totp-used:";
+const REVOKED_KEY_PREFIX: &str = "session-revoked:";
 
 #[derive(Serialize, Deserialize)]
 pub struct Credentials {
+    // Records written before versioning are of version 0.
+    #[serde(default)]
+    pub version: u32,
     pub id: String,
     pub salt: String,
     pub pwhash: String,
     pub acl: Vec<AccessControl>,
+    #[serde(default)]
+    pub totp_secret: Option<String>,
 }
 
 impl Credentials {
@@ -30,30 +48,40 @@ impl Credentials {
     where
         S: Display,
     {
-        let service = format!("{}", service);
-        for ref access in &self.acl {
-            match access.control {
-                AccessControlKind::Allow => {
-                    if access.service == "*" || access.service == service {
-                        return true;
-                    }
-                }
-                AccessControlKind::Deny => {
-                    if access.service == "*" || access.service == service {
-                        return false;
-                    }
-                }
-            }
+        AccessControl::evaluate(&self.acl, service)
+    }
+}
+
+// The record of a user in the auth Redis
+impl From<&User> for Credentials {
+    fn from(value: &User) -> Self {
+        Self {
+            version: CREDENTIALS_VERSION,
+            id: value.id.clone(),
+            salt: value.salt.clone(),
+            pwhash: value.password_hash.clone(),
+            acl: value.acl.clone(),
+            totp_secret: value.totp_secret.clone(),
         }
-        false
     }
 }
 
+// Records of version 0 are migrated as they have the same fields, while newer
+// versions are rejected rather than read with a schema they may not follow.
 impl TryFrom<&str> for Credentials {
     type Error = serde_json::error::Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        serde_json::from_str(value)
+        #[derive(Deserialize)]
+        struct Versioned {
+            #[serde(default)]
+            version: u32,
+        }
+
+        check_version(serde_json::from_str::<Versioned>(value)?.version)?;
+        let mut cred: Self = serde_json::from_str(value)?;
+        cred.version = CREDENTIALS_VERSION;
+        Ok(cred)
     }
 }
 
@@ -70,3 +98,229 @@ impl From<&Credentials> for String {
         json!(value).to_string()
     }
 }
+
+// The fields of a record stored as a Redis hash
+impl From<&Credentials> for BTreeMap<String, String> {
+    fn from(value: &Credentials) -> Self {
+        let mut fields = BTreeMap::from([
+            ("version".to_string(), value.version.to_string()),
+            ("id".to_string(), value.id.clone()),
+            ("salt".to_string(), value.salt.clone()),
+            ("pwhash".to_string(), value.pwhash.clone()),
+            ("acl".to_string(), json!(value.acl).to_string()),
+        ]);
+        if let Some(secret) = &value.totp_secret {
+            fields.insert("totp_secret".to_string(), secret.clone());
+        }
+        fields
+    }
+}
+
+impl TryFrom<&BTreeMap<String, String>> for Credentials {
+    type Error = serde_json::error::Error;
+
+    fn try_from(value: &BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        let field = |name: &'static str| match value.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => Err(Self::Error::missing_field(name)),
+        };
+        let version = match value.get("version") {
+            Some(version) => version.parse().map_err(Self::Error::custom)?,
+            None => 0,
+        };
+        check_version(version)?;
+        Ok(Self {
+            version: CREDENTIALS_VERSION,
+            id: field("id")?,
+            salt: field("salt")?,
+            pwhash: field("pwhash")?,
+            acl: serde_json::from_str(&field("acl")?)?,
+            totp_secret: value.get("totp_secret").cloned(),
+        })
+    }
+}
+
+fn check_version(version: u32) -> Result<(), serde_json::error::Error> {
+    match version {
+        0 | CREDENTIALS_VERSION => Ok(()),
+        version => Err(serde_json::error::Error::custom(format!(
+            "Unsupported version {} of credentials.",
+            version
+        ))),
+    }
+}
+
+// Names the keys of the records min-auth-admin writes to the auth Redis,
+// along with how users are encoded. Users are stored under `<prefix><user ID>`,
+// tokens under `<prefix>token:<hash>` and aliases under e.g. `<prefix>email:<email>`.
+#[derive(Clone, Debug, Default)]
+pub struct Keyspace {
+    prefix: String,
+    encoding: RecordEncoding,
+    // Aliases looked up at login
+    aliases: Vec<LoginAlias>,
+}
+
+impl Keyspace {
+    pub fn new<P>(prefix: P, encoding: RecordEncoding) -> Self
+    where
+        P: Display,
+    {
+        Self {
+            prefix: format!("{}", prefix),
+            encoding,
+            aliases: vec![],
+        }
+    }
+
+    pub fn with_aliases(mut self, aliases: &[LoginAlias]) -> Self {
+        self.aliases = aliases.to_vec();
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn encoding(&self) -> RecordEncoding {
+        self.encoding
+    }
+
+    pub fn aliases(&self) -> &[LoginAlias] {
+        &self.aliases
+    }
+
+    pub fn user<I>(&self, user_id: I) -> String
+    where
+        I: Display,
+    {
+        format!("{}{}", self.prefix, user_id)
+    }
+
+    pub fn token<H>(&self, token_hash: H) -> String
+    where
+        H: Display,
+    {
+        format!("{}{}", self.prefix, token_key(token_hash))
+    }
+
+    // Aliases are case-insensitive.
+    pub fn alias(&self, alias: LoginAlias, login: &str) -> String {
+        format!(
+            "{}{}{}",
+            self.prefix,
+            alias.key_prefix(),
+            login.to_lowercase()
+        )
+    }
+
+    // A key existing while a TOTP code cannot be used again
+    pub fn totp_used<I>(&self, user_id: I, step: i64) -> String
+    where
+        I: Display,
+    {
+        format!(
+            "{}{}{}:{}",
+            self.prefix, TOTP_USED_KEY_PREFIX, user_id, step
+        )
+    }
+
+    // A key existing while a session is revoked
+    pub fn revoked_session<I>(&self, id: I) -> String
+    where
+        I: Display,
+    {
+        format!("{}{}{}", self.prefix, REVOKED_KEY_PREFIX, id)
+    }
+
+    // Whether a key is one of the records, rather than one the auth server
+    // writes itself. Without a prefix, users are stored under their bare IDs.
+    pub fn contains(&self, key: &str) -> bool {
+        let key = match key.strip_prefix(&self.prefix) {
+            Some(key) => key,
+            None => return false,
+        };
+        if key.starts_with(TOTP_USED_KEY_PREFIX) || key.starts_with(REVOKED_KEY_PREFIX) {
+            return false;
+        }
+        match self.prefix.is_empty() {
+            true => {
+                key.starts_with(&token_key(""))
+                    || LoginAlias::ALL
+                        .iter()
+                        .any(|alias| key.starts_with(alias.key_prefix()))
+                    || !key.contains(':')
+            }
+            false => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version() {
+        let cred: Credentials =
+            r#"{"id":"foo","salt":"","pwhash":"","acl":[]}"#.try_into().unwrap();
+        assert_eq!(cred.version, CREDENTIALS_VERSION);
+        let cred: Credentials = r#"{"version":1,"id":"foo","salt":"","pwhash":"","acl":[]}"#
+            .try_into()
+            .unwrap();
+        assert_eq!(cred.id, "foo");
+        let ret: Result<Credentials, _> = r#"{"version":2,"id":"foo"}"#.try_into();
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_hash() {
+        let cred: Credentials =
+            r#"{"id":"foo","salt":"s","pwhash":"h","acl":[{"control":"Allow","service":"*"}]}"#
+                .try_into()
+                .unwrap();
+        let mut fields = BTreeMap::from(&cred);
+        assert_eq!(fields["version"], "1");
+        assert_eq!(fields["acl"], r#"[{"control":"Allow","service":"*"}]"#);
+        assert!(!fields.contains_key("totp_secret"));
+
+        let decoded = Credentials::try_from(&fields).unwrap();
+        assert_eq!(decoded.pwhash, "h");
+        assert!(decoded.allowed("any"));
+        assert!(decoded.totp_secret.is_none());
+
+        fields.insert("version".to_string(), "2".to_string());
+        assert!(Credentials::try_from(&fields).is_err());
+        fields.remove("version");
+        fields.remove("pwhash");
+        assert!(Credentials::try_from(&fields).is_err());
+    }
+
+    #[test]
+    fn test_keyspace() {
+        let keys = Keyspace::new("min-auth:cred:", RecordEncoding::Hash);
+        assert_eq!(keys.user("foo"), "min-auth:cred:foo");
+        assert_eq!(keys.token("abc"), "min-auth:cred:token:abc");
+        assert_eq!(
+            keys.alias(LoginAlias::Email, "Foo@Example.com"),
+            "min-auth:cred:email:foo@example.com"
+        );
+        assert_eq!(keys.totp_used("foo", 1), "min-auth:cred:totp-used:foo:1");
+        assert_eq!(
+            keys.revoked_session("abc"),
+            "min-auth:cred:session-revoked:abc"
+        );
+        assert!(keys.contains(&keys.token("abc")));
+        assert!(!keys.contains("foo"));
+        assert!(!keys.contains(&keys.totp_used("foo", 1)));
+        assert!(!keys.contains(&keys.revoked_session("abc")));
+
+        let keys = Keyspace::default();
+        assert_eq!(keys.user("foo"), "foo");
+        assert!(keys.contains("foo"));
+        assert!(keys.contains("token:abc"));
+        assert!(keys.contains(&keys.alias(LoginAlias::Username, "foo")));
+        assert!(!keys.contains(&keys.revoked_session("abc")));
+        assert!(!keys.contains(&keys.totp_used("foo", 1)));
+    }
+}
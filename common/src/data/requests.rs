@@ -1,13 +1,22 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use super::{users::AccessControl, DataFinder, DataLoader};
+use super::{
+    users::{validate_identity, AccessControl, User},
+    webauthn::WebAuthnCredential,
+    DataFinder, DataLoader, DataSaver, LoadReport,
+};
+use crate::error::{Error, ErrorKind};
 
 #[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
 pub enum RequestContent {
     CreateUser(CreateUserRequest),
     UpdateUser(UpdateUserRequest),
     DeleteUser(DeleteUserRequest),
+    IssueToken(IssueTokenRequest),
+    RevokeToken(RevokeTokenRequest),
+    RegisterWebAuthn(RegisterWebAuthnRequest),
+    RemoveWebAuthn(RemoveWebAuthnRequest),
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
@@ -36,6 +45,9 @@ pub struct UpdateUserRequest {
     pub acl: Option<Vec<AccessControl>>,
     pub renew_password: bool,
     pub renew_pubkey: bool,
+    // Enroll a new TOTP secret replacing the current one.
+    #[serde(default)]
+    pub renew_totp: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
@@ -43,19 +55,119 @@ pub struct DeleteUserRequest {
     pub user_id: String,
 }
 
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct IssueTokenRequest {
+    pub user_id: String,
+    pub label: String,
+    pub acl: Vec<AccessControl>,
+    // Unix time in seconds
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct RevokeTokenRequest {
+    pub user_id: String,
+    pub token_id: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct RegisterWebAuthnRequest {
+    pub user_id: String,
+    pub credential: WebAuthnCredential,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct RemoveWebAuthnRequest {
+    pub user_id: String,
+    pub credential_id: String,
+}
+
+impl Request {
+    // Files failing to load are logged and left out, or fail in strict mode.
+    pub fn load_all<P>(path: P, strict: bool) -> Result<Vec<Self>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        LoadReport::<Request>::load(path)?.into_items(strict)
+    }
+}
+
 impl Request {
-    pub fn load_all<P>(path: P) -> Result<Vec<Self>, Box<dyn std::error::Error>>
+    // Find a request by ID along with the path of the JSON file.
+    pub fn find<P>(path: P, request_id: &str) -> Result<(PathBuf, Self), Error>
     where
         P: AsRef<Path>,
     {
-        Ok(DataFinder::<Request>::new(path.as_ref())?
-            .filter_map(|x| x.ok())
-            .collect())
+        for (path, request) in DataFinder::<Request>::new(path.as_ref())?.with_paths() {
+            if let Ok(request) = request {
+                if request.id == request_id {
+                    return Ok((path, request));
+                }
+            }
+        }
+        Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Request {} was not found.", request_id),
+        ))
+    }
+}
+
+impl RequestContent {
+    // The name of the variant, e.g. "CreateUser"
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RequestContent::CreateUser(_) => "CreateUser",
+            RequestContent::UpdateUser(_) => "UpdateUser",
+            RequestContent::DeleteUser(_) => "DeleteUser",
+            RequestContent::IssueToken(_) => "IssueToken",
+            RequestContent::RevokeToken(_) => "RevokeToken",
+            RequestContent::RegisterWebAuthn(_) => "RegisterWebAuthn",
+            RequestContent::RemoveWebAuthn(_) => "RemoveWebAuthn",
+        }
+    }
+
+    // The user changed, unless one is created
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            RequestContent::CreateUser(_) => None,
+            RequestContent::UpdateUser(content) => Some(&content.user_id),
+            RequestContent::DeleteUser(content) => Some(&content.user_id),
+            RequestContent::IssueToken(content) => Some(&content.user_id),
+            RequestContent::RevokeToken(content) => Some(&content.user_id),
+            RequestContent::RegisterWebAuthn(content) => Some(&content.user_id),
+            RequestContent::RemoveWebAuthn(content) => Some(&content.user_id),
+        }
+    }
+
+    // Check the username and email a request sets against the directory,
+    // both when the request is created and when it is applied.
+    pub fn validate(&self, users: &[User]) -> Result<(), Error> {
+        match self {
+            RequestContent::CreateUser(content) => {
+                validate_identity(Some(&content.username), Some(&content.email), users)
+            }
+            RequestContent::UpdateUser(content) => {
+                if !users.iter().any(|user| user.id == content.user_id) {
+                    return Err(Error::new(
+                        ErrorKind::UnknownUser,
+                        format!("{} was not found.", content.user_id),
+                    ));
+                }
+                validate_identity(
+                    content.username.as_deref(),
+                    content.email.as_deref(),
+                    users.iter().filter(|user| user.id != content.user_id),
+                )
+            }
+            _ => Ok(()),
+        }
     }
 }
 
 impl DataLoader for Request {}
 
+impl DataSaver for Request {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -90,6 +202,7 @@ mod tests {
             assert_eq!(content.username, Some("user-1".to_string()));
             assert_eq!(content.email, Some("user-1@example.com".to_string()));
             assert_eq!(content.superuser, Some(true));
+            assert!(content.renew_totp);
             if let Some(acl) = content.acl {
                 assert_eq!(acl.len(), 2);
                 assert_eq!(acl[0].control, AccessControlKind::Allow);
@@ -114,6 +227,7 @@ mod tests {
             assert_eq!(content.email, None);
             assert_eq!(content.superuser, None);
             assert_eq!(content.acl, None);
+            assert!(!content.renew_totp);
         } else {
             panic!("Failed to parse a UpdateUser content.");
         }
@@ -129,5 +243,48 @@ mod tests {
             panic!("Failed to parse a DeleteUser content.");
         }
         assert_eq!(req.rand, 123456789);
+
+        let req = Request::load("test/requests/issue-token-1.json").unwrap();
+        assert_eq!(req.id, "issue-token-1-request");
+        if let RequestContent::IssueToken(content) = req.content {
+            assert_eq!(content.user_id, "user-1-id".to_string());
+            assert_eq!(content.label, "CI".to_string());
+            assert_eq!(content.acl.len(), 1);
+            assert_eq!(content.acl[0].control, AccessControlKind::Allow);
+            assert_eq!(content.acl[0].service, "service 1".to_string());
+            assert_eq!(content.expires_at, Some(1767225600));
+        } else {
+            panic!("Failed to parse an IssueToken content.");
+        }
+
+        let req = Request::load("test/requests/revoke-token-1.json").unwrap();
+        assert_eq!(req.id, "revoke-token-1-request");
+        if let RequestContent::RevokeToken(content) = req.content {
+            assert_eq!(content.user_id, "user-1-id".to_string());
+            assert_eq!(content.token_id, "token-1-id".to_string());
+        } else {
+            panic!("Failed to parse a RevokeToken content.");
+        }
+
+        let req = Request::load("test/requests/register-webauthn-1.json").unwrap();
+        assert_eq!(req.id, "register-webauthn-1-request");
+        if let RequestContent::RegisterWebAuthn(content) = req.content {
+            assert_eq!(content.user_id, "user-1-id".to_string());
+            assert_eq!(content.credential.id, "Y3JlZGVudGlhbC0x".to_string());
+            assert_eq!(content.credential.label, "YubiKey".to_string());
+            assert_eq!(content.credential.alg, -7);
+            assert_eq!(content.credential.sign_count, 1);
+        } else {
+            panic!("Failed to parse a RegisterWebAuthn content.");
+        }
+
+        let req = Request::load("test/requests/remove-webauthn-1.json").unwrap();
+        assert_eq!(req.id, "remove-webauthn-1-request");
+        if let RequestContent::RemoveWebAuthn(content) = req.content {
+            assert_eq!(content.user_id, "user-1-id".to_string());
+            assert_eq!(content.credential_id, "Y3JlZGVudGlhbC0x".to_string());
+        } else {
+            panic!("Failed to parse a RemoveWebAuthn content.");
+        }
     }
 }
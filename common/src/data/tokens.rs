@@ -0,0 +1,154 @@
+use super::users::AccessControl;
+use crate::utils::{genid::genid, get_hash};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt::Display;
+
+const TOKEN_PREFIX: &str = "mat_";
+const TOKEN_KEY_PREFIX: &str = "token:";
+
+// An API token held in a user JSON. The token itself is never stored.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct ApiToken {
+    pub id: String,
+    pub label: String,
+    pub token_hash: String,
+    pub acl: Vec<AccessControl>,
+    // Unix time in seconds
+    pub expires_at: Option<i64>,
+}
+
+// An API token stored in Redis with the key given by `token_key`.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct TokenCredentials {
+    pub id: String,
+    pub user_id: String,
+    pub label: String,
+    pub acl: Vec<AccessControl>,
+    pub expires_at: Option<i64>,
+}
+
+impl ApiToken {
+    // Generate a new token. The second item is the plain token to be handed
+    // to the user, which cannot be recovered later.
+    pub fn issue<S, L>(
+        secret: S,
+        label: L,
+        acl: Vec<AccessControl>,
+        expires_at: Option<i64>,
+    ) -> (Self, String)
+    where
+        S: Display,
+        L: Display,
+    {
+        let mut buf = [0u8; 32];
+        thread_rng().fill_bytes(&mut buf);
+        let token = format!("{}{}", TOKEN_PREFIX, hex::encode(buf));
+
+        let ret = Self {
+            id: genid(),
+            label: format!("{}", label),
+            token_hash: hash_token(secret, &token),
+            acl,
+            expires_at,
+        };
+        (ret, token)
+    }
+}
+
+impl TokenCredentials {
+    pub fn new<U>(user_id: U, token: &ApiToken) -> Self
+    where
+        U: Display,
+    {
+        Self {
+            id: token.id.clone(),
+            user_id: format!("{}", user_id),
+            label: token.label.clone(),
+            acl: token.acl.clone(),
+            expires_at: token.expires_at,
+        }
+    }
+
+    pub fn expired(&self, now: i64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+
+    pub fn allowed<S>(&self, service: S) -> bool
+    where
+        S: Display,
+    {
+        AccessControl::evaluate(&self.acl, service)
+    }
+}
+
+pub fn hash_token<S, T>(secret: S, token: T) -> String
+where
+    S: Display,
+    T: Display,
+{
+    get_hash(format!("{}{}", secret, token).as_str())
+}
+
+pub fn token_key<H>(token_hash: H) -> String
+where
+    H: Display,
+{
+    format!("{}{}", TOKEN_KEY_PREFIX, token_hash)
+}
+
+impl TryFrom<&str> for TokenCredentials {
+    type Error = serde_json::error::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        serde_json::from_str(value)
+    }
+}
+
+impl TryFrom<&String> for TokenCredentials {
+    type Error = serde_json::error::Error;
+
+    fn try_from(value: &String) -> Result<Self, Self::Error> {
+        value.as_str().try_into()
+    }
+}
+
+impl From<&TokenCredentials> for String {
+    fn from(value: &TokenCredentials) -> Self {
+        json!(value).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::users::AccessControlKind;
+
+    #[test]
+    fn test_issue_token() {
+        let acl = vec![AccessControl {
+            control: AccessControlKind::Allow,
+            service: "ci".to_string(),
+        }];
+        let (token, plain) = ApiToken::issue("secret", "CI", acl, Some(1000));
+        assert!(plain.starts_with(TOKEN_PREFIX));
+        assert_eq!(token.token_hash, hash_token("secret", &plain));
+        assert_ne!(token.token_hash, hash_token("other secret", &plain));
+
+        let cred = TokenCredentials::new("user-1", &token);
+        assert_eq!(cred.user_id, "user-1".to_string());
+        assert_eq!(cred.label, "CI".to_string());
+        assert!(cred.allowed("ci"));
+        assert!(!cred.allowed("other"));
+        assert!(!cred.expired(999));
+        assert!(cred.expired(1000));
+
+        let json: String = (&cred).into();
+        let parsed: TokenCredentials = (&json).try_into().unwrap();
+        assert_eq!(parsed, cred);
+    }
+}
@@ -1,9 +1,26 @@
 use crate::{
-    data::{DataFinder, DataLoader},
-    DynError,
+    data::{
+        tokens::ApiToken, webauthn::WebAuthnCredential, DataFinder, DataLoader, DataSaver,
+        LoadReport,
+    },
+    error::{Error, ErrorKind},
+    utils::{get_hash, totp},
 };
+use log::error;
+use rand::{distributions::Alphanumeric, thread_rng, Rng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::{collections::hash_map::HashMap, path::Path};
+use std::{
+    collections::hash_map::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+// Characters of a generated password
+const PASSWORD_LEN: usize = 20;
+// Characters of a username
+const USERNAME_MAX_LEN: usize = 64;
+// Bytes of an email address (RFC 5321)
+const EMAIL_MAX_LEN: usize = 254;
 
 #[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
 pub enum AccessControlKind {
@@ -21,6 +38,13 @@ pub struct User {
     pub pubkey_fpr: String,
     pub superuser: bool,
     pub acl: Vec<AccessControl>,
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+    // A base32 TOTP secret enrolled for the second factor
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub webauthn: Vec<WebAuthnCredential>,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
@@ -29,23 +53,224 @@ pub struct AccessControl {
     pub service: String,
 }
 
+impl AccessControl {
+    // The first entry matching the service decides. Nothing matched means denied.
+    pub fn evaluate<S>(acl: &[AccessControl], service: S) -> bool
+    where
+        S: Display,
+    {
+        let service = format!("{}", service);
+        for access in acl {
+            if access.service == "*" || access.service == service {
+                return access.control == AccessControlKind::Allow;
+            }
+        }
+        false
+    }
+}
+
 impl User {
-    pub fn load_all<P>(path: P) -> Result<HashMap<String, Self>, DynError>
+    // Files failing to load and users sharing a username are logged and left
+    // out, or fail in strict mode.
+    pub fn load_all<P>(path: P, strict: bool) -> Result<HashMap<String, Self>, Error>
     where
         P: AsRef<Path>,
     {
-        Ok(DataFinder::<User>::new(path.as_ref())?
-            .filter_map(|x| x.ok())
-            .map(|x| (x.username.clone(), x))
-            .collect())
+        let mut users: HashMap<String, Self> = HashMap::new();
+        for user in LoadReport::<User>::load(path)?.into_items(strict)? {
+            if let Some(other) = users.get(&user.username) {
+                let message = format!(
+                    "{} is shared by {} and {}, so {} was left out.",
+                    user.username, other.id, user.id, user.id
+                );
+                if strict {
+                    return Err(Error::new(ErrorKind::InvalidData, message));
+                }
+                error!("{}", message);
+                continue;
+            }
+            users.insert(user.username.clone(), user);
+        }
+        Ok(users)
+    }
+
+    // Find a user by ID along with the path of the JSON file.
+    pub fn find<P>(path: P, user_id: &str) -> Result<(PathBuf, Self), Error>
+    where
+        P: AsRef<Path>,
+    {
+        for (path, user) in DataFinder::<User>::new(path.as_ref())?.with_paths() {
+            if let Ok(user) = user {
+                if user.id == user_id {
+                    return Ok((path, user));
+                }
+            }
+        }
+        Err(Error::new(
+            ErrorKind::UnknownUser,
+            format!("{} was not found.", user_id),
+        ))
+    }
+
+    pub fn verify<S, P>(&self, secret: S, password: P) -> bool
+    where
+        S: Display,
+        P: Display,
+    {
+        let plain = format!("{}{}{}", secret, self.salt, password);
+        self.password_hash
+            .eq_ignore_ascii_case(&get_hash(plain.as_str()))
+    }
+
+    // Set a random password with a new salt. The password is returned
+    // to be shown to the user once.
+    pub fn renew_password<S>(&mut self, secret: S) -> String
+    where
+        S: Display,
+    {
+        let mut rng = thread_rng();
+        let password: String = (0..PASSWORD_LEN)
+            .map(|_| rng.sample(Alphanumeric) as char)
+            .collect();
+        let mut salt = [0u8; 16];
+        rng.fill_bytes(&mut salt);
+        self.salt = hex::encode(salt);
+        self.password_hash = get_hash(&format!("{}{}{}", secret, self.salt, password));
+        password
+    }
+
+    // Enroll a new TOTP secret, which is returned to be shown to the user once.
+    pub fn renew_totp(&mut self) -> String {
+        let secret = totp::generate_secret();
+        self.totp_secret = Some(secret.clone());
+        secret
     }
 }
 
 impl DataLoader for User {}
 
+impl DataSaver for User {}
+
+// Check the identity of a new or updated user against the other users in the
+// directory. Only the given items are checked, and all the problems are reported
+// at once. Usernames and emails are unique regardless of case as they are aliases,
+// and must not be the ID of another user, which is looked up before any alias.
+pub fn validate_identity<'a, I>(
+    username: Option<&str>,
+    email: Option<&str>,
+    others: I,
+) -> Result<(), Error>
+where
+    I: IntoIterator<Item = &'a User>,
+{
+    let mut problems = vec![];
+    if let Some(username) = username {
+        problems.extend(username_problems(username));
+    }
+    if let Some(email) = email {
+        problems.extend(email_problems(email));
+    }
+    for other in others {
+        for (name, login, alias) in [
+            ("Username", username, &other.username),
+            ("Email", email, &other.email),
+        ] {
+            let login = match login {
+                Some(login) => login,
+                None => continue,
+            };
+            if alias.to_lowercase() == login.to_lowercase() {
+                problems.push(format!("{} {:?} is taken by {}.", name, login, other.id));
+            }
+            if other.id.to_lowercase() == login.to_lowercase() {
+                problems.push(format!("{} {:?} is the ID of {}.", name, login, other.id));
+            }
+        }
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(Error::new(ErrorKind::BadRequest, problems.join(" "))),
+    }
+}
+
+// Letters, digits, spaces and "._-" without leading or trailing spaces
+fn username_problems(username: &str) -> Vec<String> {
+    let mut problems = vec![];
+    let len = username.chars().count();
+    if len == 0 || len > USERNAME_MAX_LEN {
+        problems.push(format!(
+            "Username {:?} must be 1 to {} characters.",
+            username, USERNAME_MAX_LEN
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_alphanumeric() || " ._-".contains(c))
+    {
+        problems.push(format!(
+            "Username {:?} may only contain letters, digits, spaces and \"._-\".",
+            username
+        ));
+    }
+    if username.trim() != username {
+        problems.push(format!(
+            "Username {:?} must not start or end with a space.",
+            username
+        ));
+    }
+    problems
+}
+
+// A practical subset of RFC 5322: `local@domain` with a dotted domain
+fn email_problems(email: &str) -> Vec<String> {
+    let mut problems = vec![];
+    let mut invalid = |reason: &str| problems.push(format!("Email {:?} {}.", email, reason));
+    if email.len() > EMAIL_MAX_LEN {
+        invalid(&format!("is longer than {} bytes", EMAIL_MAX_LEN));
+    }
+    if email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        invalid("contains spaces or control characters");
+    }
+    // Logins with a colon are rejected by the auth server.
+    if email.contains(':') {
+        invalid("contains a colon");
+    }
+    let (local, domain) = match email.split_once('@') {
+        Some((local, domain)) => (local, domain),
+        None => {
+            invalid("has no @");
+            return problems;
+        }
+    };
+    if local.is_empty() {
+        invalid("has no local part");
+    }
+    if local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        invalid("has misplaced dots in the local part");
+    }
+    if domain.contains('@') {
+        invalid("has more than one @");
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() < 2
+        || !labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+    {
+        invalid("has an invalid domain");
+    }
+    problems
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::data::webauthn::COSE_EDDSA;
+    use std::fs::{copy, read_dir, write};
+    use tempfile::tempdir;
 
     #[test]
     fn test_load_user() {
@@ -62,11 +287,60 @@ mod tests {
         assert_eq!(user.acl[0].service, "service 1".to_string());
         assert_eq!(user.acl[1].control, AccessControlKind::Deny);
         assert_eq!(user.acl[1].service, "*".to_string());
+        assert_eq!(user.tokens.len(), 1);
+        assert_eq!(user.tokens[0].id, "foo1 token".to_string());
+        assert_eq!(user.tokens[0].label, "foo1 label".to_string());
+        assert_eq!(user.tokens[0].token_hash, "foo1 token hash".to_string());
+        assert_eq!(user.tokens[0].acl.len(), 1);
+        assert_eq!(user.tokens[0].expires_at, None);
+        assert_eq!(user.totp_secret, Some("FOO1TOTP".to_string()));
+        assert_eq!(user.webauthn.len(), 1);
+        assert_eq!(user.webauthn[0].id, "Zm9vMSBjcmVkZW50aWFs".to_string());
+        assert_eq!(user.webauthn[0].label, "foo1 key".to_string());
+        assert_eq!(user.webauthn[0].alg, COSE_EDDSA);
+        assert_eq!(user.webauthn[0].sign_count, 0);
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut user = User::load("test/users/foo2.json").unwrap();
+        user.password_hash = get_hash("secretfoo2 saltpassword");
+        assert!(user.verify("secret", "password"));
+        assert!(!user.verify("secret", "other"));
+        assert!(!user.verify("other", "password"));
+    }
+
+    #[test]
+    fn test_find() {
+        let (path, user) = User::find("test/users", "Baz").unwrap();
+        assert_eq!(path, Path::new("test/users/bar/baz/baz.json"));
+        assert_eq!(user.username, "Baz Baz".to_string());
+        let e = User::find("test/users", "Qux").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnknownUser);
+    }
+
+    #[test]
+    fn test_renew_password() {
+        let mut user = User::load("test/users/foo2.json").unwrap();
+        let password = user.renew_password("secret");
+        assert_eq!(password.len(), PASSWORD_LEN);
+        assert_ne!(user.salt, "foo2 salt".to_string());
+        assert!(user.verify("secret", &password));
+        assert_ne!(user.renew_password("secret"), password);
+    }
+
+    #[test]
+    fn test_renew_totp() {
+        let mut user = User::load("test/users/foo2.json").unwrap();
+        assert_eq!(user.totp_secret, None);
+        let secret = user.renew_totp();
+        assert_eq!(user.totp_secret, Some(secret.clone()));
+        assert_ne!(user.renew_totp(), secret);
     }
 
     #[test]
     fn test_load_users() {
-        let users = User::load_all("test/users").unwrap();
+        let users = User::load_all("test/users", true).unwrap();
         assert_eq!(users.len(), 4);
 
         let user = users.get("Foo1 Foo1").unwrap();
@@ -82,6 +356,8 @@ mod tests {
         assert_eq!(user.acl[0].service, "service 1".to_string());
         assert_eq!(user.acl[1].control, AccessControlKind::Deny);
         assert_eq!(user.acl[1].service, "*".to_string());
+        assert_eq!(user.tokens.len(), 1);
+        assert_eq!(user.totp_secret, Some("FOO1TOTP".to_string()));
 
         let user = users.get("Foo2 Foo2").unwrap();
         assert_eq!(user.id, "Foo2".to_string());
@@ -123,4 +399,85 @@ mod tests {
         assert_eq!(user.acl[0].control, AccessControlKind::Deny);
         assert_eq!(user.acl[0].service, "*".to_string());
     }
+
+    #[test]
+    fn test_load_corrupt_users() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        copy("test/users/foo2.json", dir.join("foo2.json")).unwrap();
+        write(dir.join("broken.json"), "{").unwrap();
+
+        let report = LoadReport::<User>::load(dir).unwrap();
+        assert_eq!(report.items.len(), 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, dir.join("broken.json"));
+
+        assert_eq!(User::load_all(dir, false).unwrap().len(), 1);
+        let e = User::load_all(dir, true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(e.to_string().contains("broken.json"));
+    }
+
+    #[test]
+    fn test_save_user() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.join("foo2.json");
+
+        let mut user = User::load("test/users/foo2.json").unwrap();
+        user.save_new(&path).unwrap();
+        let e = user.save_new(&path).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+
+        user.email = "renamed@example.com".to_string();
+        user.save(&path).unwrap();
+        assert_eq!(User::load(&path).unwrap().email, user.email);
+        // Only the record is left behind.
+        assert_eq!(read_dir(dir).unwrap().count(), 1);
+
+        User::remove(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_validate_identity() {
+        let users: Vec<User> = User::load_all("test/users", true)
+            .unwrap()
+            .into_values()
+            .collect();
+        assert!(validate_identity(Some("new.user_1"), Some("new@example.com"), &users).is_ok());
+        // Only the given items are checked.
+        assert!(validate_identity(None, None, &users).is_ok());
+
+        let e = validate_identity(Some("foo1 FOO1"), Some("FOO2@example.com"), &users).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadRequest);
+        let message = e.to_string();
+        assert!(message.contains("is taken by Foo1."));
+        assert!(message.contains("is taken by Foo2."));
+
+        // The ID of another user would log in as that user.
+        let e = validate_identity(Some("foo2"), None, &users).unwrap_err();
+        assert!(e.to_string().contains("is the ID of Foo2."));
+
+        for username in ["", " lead", "semi;colon", &"x".repeat(USERNAME_MAX_LEN + 1)] {
+            assert!(validate_identity(Some(username), None, &users).is_err());
+        }
+        for email in [
+            "no-at",
+            "@example.com",
+            "a@@example.com",
+            "a..b@example.com",
+            "a b@example.com",
+            "a:b@example.com",
+            "a@localhost",
+            "a@-example.com",
+            "a@example..com",
+        ] {
+            assert!(
+                validate_identity(None, Some(email), &users).is_err(),
+                "{}",
+                email
+            );
+        }
+    }
 }
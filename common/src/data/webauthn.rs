@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+// COSE algorithm identifiers supported for WebAuthn
+pub const COSE_ES256: i64 = -7;
+pub const COSE_EDDSA: i64 = -8;
+
+// A WebAuthn credential registered by a user.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct WebAuthnCredential {
+    // The credential ID in base64url
+    pub id: String,
+    pub label: String,
+    // COSE_ES256 or COSE_EDDSA
+    pub alg: i64,
+    // An uncompressed SEC1 point for ES256 or 32 bytes for EdDSA in base64url
+    pub public_key: String,
+    // The signature counter at registration. The latest one is kept in Redis.
+    pub sign_count: u32,
+}
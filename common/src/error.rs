@@ -1,19 +1,149 @@
+use http::StatusCode;
 use std::fmt::Display;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    // The request has no credentials.
+    NoHeader,
+    // The credentials cannot be parsed.
+    MalformedHeader,
+    // The requested user is not registered.
+    UnknownUser,
+    // The password does not match.
+    BadPassword,
+    // The bearer token is not registered.
+    InvalidToken,
+    // The bearer token has expired.
+    ExpiredToken,
+    // NGINX failed to verify the client certificate.
+    InvalidCertificate,
+    // The client certificate is not mapped to any user.
+    UnknownCertificate,
+    // The session cookie cannot be opened or has been revoked.
+    InvalidSession,
+    // The session cookie has timed out.
+    ExpiredSession,
+    // The service requires a TOTP code, but none was given or enrolled.
+    TotpRequired,
+    // The TOTP code does not match or has already been used.
+    BadTotp,
+    // A WebAuthn assertion is required, but none was given.
+    WebAuthnRequired,
+    // The WebAuthn response cannot be verified.
+    BadWebAuthn,
+    // The user is not allowed to use the requested service.
+    ServiceDenied,
+    // The request itself is wrong (e.g. no service was specified).
+    BadRequest,
+    // No route matches the request.
+    NotFound,
+    // A backend (e.g. Redis) cannot be reached.
+    Unavailable,
+    // A backend returned an error.
+    Backend,
+    // Stored data cannot be (de)serialized.
+    InvalidData,
+    // A file cannot be read or written.
+    Io,
+    // A config cannot be (de)serialized.
+    InvalidConfig,
+    Internal,
+}
+
+impl ErrorKind {
+    // Stable codes for logs, audit records and monitoring.
+    // Do not change them once released.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::NoHeader => "no_header",
+            ErrorKind::MalformedHeader => "malformed_header",
+            ErrorKind::UnknownUser => "unknown_user",
+            ErrorKind::BadPassword => "bad_password",
+            ErrorKind::InvalidToken => "invalid_token",
+            ErrorKind::ExpiredToken => "expired_token",
+            ErrorKind::InvalidCertificate => "invalid_certificate",
+            ErrorKind::UnknownCertificate => "unknown_certificate",
+            ErrorKind::InvalidSession => "invalid_session",
+            ErrorKind::ExpiredSession => "expired_session",
+            ErrorKind::TotpRequired => "totp_required",
+            ErrorKind::BadTotp => "bad_totp",
+            ErrorKind::WebAuthnRequired => "webauthn_required",
+            ErrorKind::BadWebAuthn => "bad_webauthn",
+            ErrorKind::ServiceDenied => "service_denied",
+            ErrorKind::BadRequest => "bad_request",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Unavailable => "backend_unavailable",
+            ErrorKind::Backend => "backend_error",
+            ErrorKind::InvalidData => "invalid_data",
+            ErrorKind::Io => "io_error",
+            ErrorKind::InvalidConfig => "invalid_config",
+            ErrorKind::Internal => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::NoHeader
+            | ErrorKind::MalformedHeader
+            | ErrorKind::UnknownUser
+            | ErrorKind::BadPassword
+            | ErrorKind::InvalidToken
+            | ErrorKind::ExpiredToken
+            | ErrorKind::InvalidCertificate
+            | ErrorKind::UnknownCertificate
+            | ErrorKind::InvalidSession
+            | ErrorKind::ExpiredSession
+            | ErrorKind::TotpRequired
+            | ErrorKind::BadTotp
+            | ErrorKind::WebAuthnRequired
+            | ErrorKind::BadWebAuthn => StatusCode::UNAUTHORIZED,
+            ErrorKind::ServiceDenied => StatusCode::FORBIDDEN,
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Backend
+            | ErrorKind::InvalidData
+            | ErrorKind::Io
+            | ErrorKind::InvalidConfig
+            | ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
 #[derive(Debug)]
 pub struct Error {
+    kind: ErrorKind,
     message: String,
 }
 
 impl Error {
-    pub fn new<T>(message: T) -> Self
+    pub fn new<T>(kind: ErrorKind, message: T) -> Self
     where
         T: Display,
     {
         Self {
+            kind,
             message: format!("{}", message),
         }
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.kind.status()
+    }
 }
 
 impl Display for Error {
@@ -23,3 +153,80 @@ impl Display for Error {
 }
 
 impl std::error::Error for Error {}
+
+impl From<redis::RedisError> for Error {
+    fn from(value: redis::RedisError) -> Self {
+        let kind = if value.is_connection_refusal()
+            || value.is_connection_dropped()
+            || value.is_timeout()
+            || value.is_io_error()
+        {
+            ErrorKind::Unavailable
+        } else {
+            ErrorKind::Backend
+        };
+        Self::new(kind, value)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::new(ErrorKind::InvalidData, value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::new(ErrorKind::Io, value)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(value: toml::de::Error) -> Self {
+        Self::new(ErrorKind::InvalidConfig, value)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(value: toml::ser::Error) -> Self {
+        Self::new(ErrorKind::InvalidConfig, value)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(value: http::Error) -> Self {
+        Self::new(ErrorKind::Internal, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        assert_eq!(ErrorKind::BadPassword.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(ErrorKind::ServiceDenied.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            ErrorKind::Unavailable.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            ErrorKind::InvalidData.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn test_from() {
+        let e: Error = serde_json::from_str::<u32>("x").unwrap_err().into();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(e.code(), "invalid_data");
+
+        let e: Error = std::fs::read("test/no-such-file").unwrap_err().into();
+        assert_eq!(e.kind(), ErrorKind::Io);
+
+        let e: Error = toml::from_str::<toml::Table>("=").unwrap_err().into();
+        assert_eq!(e.kind(), ErrorKind::InvalidConfig);
+    }
+}
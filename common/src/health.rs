@@ -0,0 +1,148 @@
+use crate::error::{Error, ErrorKind};
+use http::{header, Response, StatusCode};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+use tokio::time::timeout;
+
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Fail,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+impl ComponentHealth {
+    pub fn ok() -> Self {
+        Self {
+            status: HealthStatus::Ok,
+            message: None,
+        }
+    }
+
+    pub fn fail<T>(message: T) -> Self
+    where
+        T: ToString,
+    {
+        Self {
+            status: HealthStatus::Fail,
+            message: Some(message.to_string()),
+        }
+    }
+}
+
+impl HealthReport {
+    pub fn new() -> Self {
+        Self {
+            status: HealthStatus::Ok,
+            components: BTreeMap::new(),
+        }
+    }
+
+    pub fn add<N>(&mut self, name: N, health: ComponentHealth)
+    where
+        N: ToString,
+    {
+        if health.status == HealthStatus::Fail {
+            self.status = HealthStatus::Fail;
+        }
+        self.components.insert(name.to_string(), health);
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == HealthStatus::Ok
+    }
+}
+
+impl Default for HealthReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&HealthReport> for String {
+    fn from(value: &HealthReport) -> Self {
+        serde_json::json!(value).to_string()
+    }
+}
+
+// A JSON response for /healthz and /readyz, 503 if any component failed
+pub fn health<B>(report: &HealthReport) -> Result<Response<B>, Error>
+where
+    B: From<Vec<u8>>,
+{
+    let status = if report.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body: String = report.into();
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.into_bytes().into())?)
+}
+
+// PING a Redis server, giving up after a few seconds.
+pub async fn check_redis(client: &redis::Client) -> ComponentHealth {
+    let ping = async {
+        let mut conn = client.get_multiplexed_async_connection().await?;
+        redis::cmd("PING")
+            .query_async::<String>(&mut conn)
+            .await
+            .map_err(Error::from)
+    };
+    match timeout(REDIS_TIMEOUT, ping).await {
+        Ok(Ok(_)) => ComponentHealth::ok(),
+        Ok(Err(e)) => ComponentHealth::fail(e),
+        Err(_) => ComponentHealth::fail(Error::new(ErrorKind::Unavailable, "PING timed out.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = HealthReport::new();
+        report.add("config", ComponentHealth::ok());
+        assert!(report.is_ok());
+        assert_eq!(
+            String::from(&report),
+            r#"{"components":{"config":{"status":"ok"}},"status":"ok"}"#
+        );
+
+        report.add("redis", ComponentHealth::fail("Connection refused"));
+        assert!(!report.is_ok());
+        assert_eq!(report.components["config"].status, HealthStatus::Ok);
+        assert_eq!(report.components["redis"].status, HealthStatus::Fail);
+    }
+
+    #[test]
+    fn test_health() {
+        let mut report = HealthReport::new();
+        let res: Response<Vec<u8>> = health(&report).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+
+        report.add("redis", ComponentHealth::fail("Connection refused"));
+        let res: Response<Vec<u8>> = health(&report).unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body(), &Vec::from(String::from(&report)));
+    }
+}
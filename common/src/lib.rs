@@ -1,6 +1,8 @@
 pub mod config;
 pub mod data;
 pub mod error;
+pub mod health;
+pub mod tls;
 pub mod utils;
 
 pub type DynError = Box<dyn std::error::Error + Send + Sync>;
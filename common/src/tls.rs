@@ -0,0 +1,293 @@
+use crate::{
+    config::socket::TlsConfig,
+    error::{Error, ErrorKind},
+};
+use log::{error, info};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use std::{
+    fs::{metadata, File},
+    io::{self, BufReader},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+
+// How often the PEM files are checked for modification
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// Terminates TLS with the certificate in the config.
+// The certificate is replaced without a restart when its files are modified.
+#[derive(Debug)]
+pub struct TlsTerminator {
+    config: TlsConfig,
+    state: RwLock<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    server: Arc<ServerConfig>,
+    modified: Vec<Option<SystemTime>>,
+    checked: Instant,
+}
+
+impl TlsTerminator {
+    pub fn load(config: &TlsConfig) -> Result<Self, Error> {
+        let state = State {
+            server: server_config(config)?,
+            modified: modified(config),
+            checked: Instant::now(),
+        };
+        Ok(Self {
+            config: config.clone(),
+            state: RwLock::new(state),
+        })
+    }
+
+    pub async fn accept<IO>(&self, stream: IO) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        if self.state().checked.elapsed() >= RELOAD_INTERVAL {
+            if let Err(e) = self.reload() {
+                // Keep the current certificate, e.g. while the files are being replaced.
+                error!("{} ({})", e, e.code());
+            }
+        }
+        let server = Arc::clone(&self.state().server);
+        tokio_rustls::TlsAcceptor::from(server).accept(stream).await
+    }
+
+    // Returns true if the certificate was reloaded.
+    pub fn reload(&self) -> Result<bool, Error> {
+        let modified = modified(&self.config);
+        let mut state = self
+            .state
+            .write()
+            .map_err(|e| Error::new(ErrorKind::Internal, e))?;
+        state.checked = Instant::now();
+        if modified == state.modified {
+            return Ok(false);
+        }
+
+        state.server = server_config(&self.config)?;
+        state.modified = modified;
+        info!("The certificate {} was reloaded.", self.config.cert);
+        Ok(true)
+    }
+
+    fn state(&self) -> std::sync::RwLockReadGuard<'_, State> {
+        // A writer never panics while holding the lock.
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, Error> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::new(ErrorKind::InvalidConfig, e))?;
+
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| Error::new(ErrorKind::InvalidConfig, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match config.client_auth_optional {
+                true => verifier.allow_unauthenticated(),
+                false => verifier,
+            };
+            builder.with_client_cert_verifier(
+                verifier
+                    .build()
+                    .map_err(|e| Error::new(ErrorKind::InvalidConfig, e))?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server = builder
+        .with_single_cert(load_certs(&config.cert)?, load_key(&config.key)?)
+        .map_err(|e| Error::new(ErrorKind::InvalidConfig, e))?;
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(server))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidConfig,
+            format!("No certificate was found in {}.", path),
+        ));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(Error::new(
+            ErrorKind::InvalidConfig,
+            format!("No private key was found in {}.", path),
+        )),
+    }
+}
+
+fn modified(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [
+        Some(&config.cert),
+        Some(&config.key),
+        config.client_ca.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{pki_types::ServerName, ClientConfig};
+    use std::{fs::copy, path::Path};
+    use tempfile::tempdir;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    const DIR: &str = "test/tls";
+
+    fn config(client_ca: bool) -> TlsConfig {
+        TlsConfig {
+            cert: format!("{}/server.pem", DIR),
+            key: format!("{}/server.key", DIR),
+            client_ca: client_ca.then(|| format!("{}/ca.pem", DIR)),
+            client_auth_optional: false,
+        }
+    }
+
+    fn connector(client_cert: bool) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&format!("{}/ca.pem", DIR)).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            true => builder
+                .with_client_auth_cert(
+                    load_certs(&format!("{}/client.pem", DIR)).unwrap(),
+                    load_key(&format!("{}/client.key", DIR)).unwrap(),
+                )
+                .unwrap(),
+            false => builder.with_no_client_auth(),
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    // Returns true if a byte went through the handshake.
+    async fn handshake(terminator: TlsTerminator, connector: TlsConnector) -> bool {
+        let (client, server) = duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = terminator.accept(server).await?;
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf).await?;
+            Ok::<u8, io::Error>(buf[0])
+        });
+        let name = ServerName::try_from("localhost").unwrap();
+        // Keep the client open until the server has read the byte.
+        let _client = match connector.connect(name, client).await {
+            Ok(mut stream) => {
+                let _ = stream.write_all(b"x").await;
+                let _ = stream.flush().await;
+                Some(stream)
+            }
+            Err(_) => None,
+        };
+        matches!(server.await.unwrap(), Ok(b'x'))
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let terminator = TlsTerminator::load(&config(false)).unwrap();
+        assert!(handshake(terminator, connector(false)).await);
+
+        // A client certificate is required.
+        let terminator = TlsTerminator::load(&config(true)).unwrap();
+        assert!(handshake(terminator, connector(true)).await);
+        let terminator = TlsTerminator::load(&config(true)).unwrap();
+        assert!(!handshake(terminator, connector(false)).await);
+
+        let mut optional = config(true);
+        optional.client_auth_optional = true;
+        let terminator = TlsTerminator::load(&optional).unwrap();
+        assert!(handshake(terminator, connector(false)).await);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut config = config(false);
+        config.key = format!("{}/client.key", DIR);
+        let e = TlsTerminator::load(&config).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidConfig);
+
+        config.key = format!("{}/server.pem", DIR);
+        let e = TlsTerminator::load(&config).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidConfig);
+
+        config.cert = format!("{}/none.pem", DIR);
+        let e = TlsTerminator::load(&config).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Io);
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        copy(Path::new(DIR).join("server.pem"), path("cert.pem")).unwrap();
+        copy(Path::new(DIR).join("server.key"), path("key.pem")).unwrap();
+        let config = TlsConfig {
+            cert: path("cert.pem"),
+            key: path("key.pem"),
+            client_ca: None,
+            client_auth_optional: false,
+        };
+
+        let terminator = TlsTerminator::load(&config).unwrap();
+        assert!(!terminator.reload().unwrap());
+
+        // A broken pair keeps the current certificate.
+        copy(Path::new(DIR).join("client.pem"), path("cert.pem")).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(path("cert.pem"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(terminator.reload().is_err());
+
+        copy(Path::new(DIR).join("client.key"), path("key.pem")).unwrap();
+        File::options()
+            .write(true)
+            .open(path("key.pem"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(terminator.reload().unwrap());
+        assert!(!terminator.reload().unwrap());
+    }
+}
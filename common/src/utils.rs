@@ -2,7 +2,9 @@ use sha2::{Digest, Sha256};
 
 pub mod base35;
 pub mod genid;
+pub mod seal;
 pub mod threadid;
+pub mod totp;
 
 pub fn get_hash(password: &str) -> String {
     let encoded = password.as_bytes();
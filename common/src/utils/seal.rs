@@ -0,0 +1,66 @@
+use crate::error::{Error, ErrorKind};
+use aes_gcm::{
+    aead::{Aead, OsRng, Payload},
+    AeadCore, Aes256Gcm, Nonce,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+const NONCE_LEN: usize = 12;
+
+// Seal a value in JSON with AES-256-GCM, the nonce first. `aad` binds it
+// to where it is used (e.g. the cookie name) without being sealed itself.
+pub fn seal<T>(cipher: &Aes256Gcm, value: &T, aad: &[u8]) -> Result<Vec<u8>, Error>
+where
+    T: Serialize,
+{
+    let plain = serde_json::to_vec(value)?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload { msg: &plain, aad };
+    let sealed = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|e| Error::new(ErrorKind::Internal, e))?;
+
+    let mut ret = nonce.to_vec();
+    ret.extend(sealed);
+    Ok(ret)
+}
+
+// Any value not sealed with the key and `aad` is an invalid session.
+pub fn open<T>(cipher: &Aes256Gcm, sealed: &[u8], aad: &[u8]) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    if sealed.len() <= NONCE_LEN {
+        return Err(Error::new(ErrorKind::InvalidSession, "Too short session."));
+    }
+    let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+    let payload = Payload { msg: sealed, aad };
+    let plain = cipher
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|e| Error::new(ErrorKind::InvalidSession, e))?;
+    serde_json::from_slice(&plain).map_err(|e| Error::new(ErrorKind::InvalidSession, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::KeyInit;
+
+    #[test]
+    fn test_seal_and_open() {
+        let cipher = Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng));
+        let sealed = seal(&cipher, &vec!["a", "b"], b"cookie").unwrap();
+        let opened: Vec<String> = open(&cipher, &sealed, b"cookie").unwrap();
+        assert_eq!(opened, vec!["a", "b"]);
+
+        let other = Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng));
+        for (cipher, sealed, aad) in [
+            (&other, sealed.as_slice(), b"cookie".as_slice()),
+            (&cipher, sealed.as_slice(), b"other".as_slice()),
+            (&cipher, &sealed[..NONCE_LEN], b"cookie".as_slice()),
+        ] {
+            let e = open::<Vec<String>>(cipher, sealed, aad).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidSession);
+        }
+    }
+}
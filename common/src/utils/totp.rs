@@ -0,0 +1,109 @@
+use base32::Alphabet;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use sha1::Sha1;
+
+// RFC 6238 with the parameters every authenticator app supports
+pub const DIGITS: usize = 6;
+pub const STEP: i64 = 30;
+
+const SECRET_LEN: usize = 20;
+const ALPHABET: Alphabet = Alphabet::Rfc4648 { padding: false };
+
+// A new secret in base32 to be registered in an authenticator app.
+pub fn generate_secret() -> String {
+    let mut buf = [0u8; SECRET_LEN];
+    thread_rng().fill_bytes(&mut buf);
+    base32::encode(ALPHABET, &buf)
+}
+
+// The code for the step, or None if the secret is not base32.
+pub fn code(secret: &str, step: i64) -> Option<String> {
+    let key = base32::decode(ALPHABET, &secret.replace(' ', "").to_uppercase())?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation of RFC 4226
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bin = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    Some(format!(
+        "{:0width$}",
+        bin % 10u32.pow(DIGITS as u32),
+        width = DIGITS
+    ))
+}
+
+// Returns the matched step to be recorded against replays.
+// `skew` steps before and after the current one are accepted.
+pub fn verify(secret: &str, code: &str, now: i64, skew: u64) -> Option<i64> {
+    if code.len() != DIGITS || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let current = now / STEP;
+    let skew = skew as i64;
+    (current - skew..=current + skew)
+        .find(|step| self::code(secret, *step).as_deref() == Some(code))
+}
+
+// Split "password123456" into the password and the code.
+pub fn split_code(password: &str) -> Option<(&str, &str)> {
+    if password.len() <= DIGITS || !password.is_char_boundary(password.len() - DIGITS) {
+        return None;
+    }
+    let (password, code) = password.split_at(password.len() - DIGITS);
+    if code.bytes().all(|c| c.is_ascii_digit()) {
+        Some((password, code))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "12345678901234567890" of RFC 6238 Appendix B
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_code() {
+        assert_eq!(code(SECRET, 59 / STEP), Some("287082".to_string()));
+        assert_eq!(code(SECRET, 1111111109 / STEP), Some("081804".to_string()));
+        assert_eq!(code(SECRET, 1234567890 / STEP), Some("005924".to_string()));
+        assert_eq!(code(SECRET, 2000000000 / STEP), Some("279037".to_string()));
+        assert_eq!(code("not base32!", 1), None);
+
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        assert!(code(&secret, 1).is_some());
+    }
+
+    #[test]
+    fn test_verify() {
+        assert_eq!(
+            verify(SECRET, "081804", 1111111109, 0),
+            Some(1111111109 / STEP)
+        );
+        assert_eq!(
+            verify(SECRET, "081804", 1111111109 + STEP, 1),
+            Some(1111111109 / STEP)
+        );
+        assert_eq!(verify(SECRET, "081804", 1111111109 + STEP, 0), None);
+        assert_eq!(verify(SECRET, "81804", 1111111109, 0), None);
+        assert_eq!(verify(SECRET, "08180a", 1111111109, 0), None);
+    }
+
+    #[test]
+    fn test_split_code() {
+        assert_eq!(split_code("secret123456"), Some(("secret", "123456")));
+        assert_eq!(split_code("secret12345a"), None);
+        assert_eq!(split_code("123456"), None);
+        assert_eq!(split_code("pässwörd"), None);
+    }
+}